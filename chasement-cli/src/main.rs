@@ -0,0 +1,241 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process,
+};
+
+use chasement::{analysis, InstructionSet, RunOutcome, Vm};
+
+const USAGE: &str = "\
+Usage: chasement-cli [OPTIONS] [PROGRAM]
+
+Runs the program at PROGRAM, or the program read from stdin if no path is given.
+
+Options:
+    --help                     Print this help
+    --version                  Print the version
+    --list-instructions        Print the opcodes of all instructions and literals
+    --max-fuel N               Stop with an error after N executed instructions
+    --trace                    Print every executed instruction and the stack to stderr
+    --dump-stack               Print both stacks to stderr once the program ended
+    --check-brackets           Fail before running if brackets are unmatched or crossing
+    --input-file PATH          Read the input of the program from PATH instead of stdin
+    --output-file PATH         Write the output of the program to PATH instead of stdout
+    --analyze-corpus DIR       Print token statistics of all .chase files in DIR
+    --minimize PATH            Print a smaller program failing like the one at PATH, only
+                               with the devtools feature";
+
+/// The parsed command line arguments
+#[derive(Debug, Default)]
+struct Options {
+    program: Option<PathBuf>,
+    list_instructions: bool,
+    max_fuel: Option<u64>,
+    trace: bool,
+    dump_stack: bool,
+    check_brackets: bool,
+    input_file: Option<PathBuf>,
+    output_file: Option<PathBuf>,
+}
+
+fn main() {
+    let options = parse_args(env::args().skip(1));
+
+    if options.list_instructions {
+        list_instructions();
+        return;
+    }
+
+    let program = options
+        .program
+        .as_ref()
+        .map(|path| fs::read(path).unwrap_or_else(|err| fail(path, err)))
+        .unwrap_or_else(|| {
+            let stdin = io::stdin();
+            let mut stdin = stdin.lock();
+            let mut v = Vec::new();
+
+            stdin.read_to_end(&mut v).unwrap();
+            v
+        });
+
+    let mut vm = Vm::new(InstructionSet::standard(), &program as &[u8]);
+    if let Some(path) = &options.input_file {
+        let file = File::open(path).unwrap_or_else(|err| fail(path, err));
+        vm.get_context_mut().set_input(io::BufReader::new(file));
+    }
+    if let Some(path) = &options.output_file {
+        let file = File::create(path).unwrap_or_else(|err| fail(path, err));
+        vm.get_context_mut().set_output(io::BufWriter::new(file));
+    }
+    if options.check_brackets {
+        vm.enable_bracket_checks();
+    }
+    if options.trace {
+        vm.set_trace_hook(Box::new(|pc, opcode, ctx| {
+            let stack = ctx.stack_iter().collect::<Vec<_>>();
+            eprintln!("{:>6} {:?} {:?}", pc, opcode as char, stack);
+        }));
+    }
+
+    let res = match options.max_fuel {
+        Some(fuel) => vm.run_with_limit(fuel),
+        None => vm.run().map(|_| RunOutcome::Completed),
+    };
+
+    // A completed run flushed its output already, keep what a run out of fuel printed so far
+    let res = match res {
+        Ok(RunOutcome::LimitReached) => vm
+            .get_context()
+            .flush_output()
+            .map(|_| RunOutcome::LimitReached),
+        res => res,
+    };
+    if options.dump_stack {
+        let ctx = vm.get_context();
+        eprintln!("Main: {:#?}", ctx.stack_iter().collect::<Vec<_>>());
+        eprintln!("Aux: {:#?}", ctx.aux_stack_iter().collect::<Vec<_>>());
+    }
+    match res {
+        Ok(RunOutcome::Completed) => (),
+        Ok(RunOutcome::LimitReached) => {
+            eprintln!(
+                "ERROR: Ran out of fuel after {} instructions",
+                options.max_fuel.unwrap_or_default()
+            );
+            process::exit(1)
+        }
+        Err(err) => {
+            eprintln!("ERROR: {}", err);
+            process::exit(1)
+        }
+    }
+}
+
+/// Parse the arguments after the program name. Flags which only print something and exit, like
+/// `--help` or `--analyze-corpus`, are handled right away.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Options {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().unwrap_or_else(|| {
+                eprintln!("ERROR: {} needs a value", name);
+                process::exit(1)
+            })
+        };
+
+        match arg.as_str() {
+            "--help" => {
+                println!("{}", USAGE);
+                process::exit(0)
+            }
+            "--version" => {
+                println!("chasement-cli {}", env!("CARGO_PKG_VERSION"));
+                process::exit(0)
+            }
+            "--analyze-corpus" => {
+                analyze_corpus(Path::new(&value("--analyze-corpus")));
+                process::exit(0)
+            }
+            #[cfg(feature = "devtools")]
+            "--minimize" => {
+                minimize(Path::new(&value("--minimize")));
+                process::exit(0)
+            }
+            "--list-instructions" => options.list_instructions = true,
+            "--max-fuel" => {
+                let fuel = value("--max-fuel");
+                options.max_fuel = Some(fuel.parse().unwrap_or_else(|_| {
+                    eprintln!("ERROR: --max-fuel needs a number, found {:?}", fuel);
+                    process::exit(1)
+                }));
+            }
+            "--trace" => options.trace = true,
+            "--dump-stack" => options.dump_stack = true,
+            "--check-brackets" => options.check_brackets = true,
+            "--input-file" => options.input_file = Some(value("--input-file").into()),
+            "--output-file" => options.output_file = Some(value("--output-file").into()),
+            flag if flag.starts_with("--") => {
+                eprintln!("ERROR: Unknown option {}\n\n{}", flag, USAGE);
+                process::exit(1)
+            }
+            _ if options.program.is_some() => {
+                eprintln!("ERROR: Only one program can be run\n\n{}", USAGE);
+                process::exit(1)
+            }
+            path => options.program = Some(path.into()),
+        }
+    }
+    options
+}
+
+/// Print the opcodes of the default instruction set
+fn list_instructions() {
+    let instructions = InstructionSet::<Vec<u8>>::standard();
+    let show = |opcodes: Vec<u8>| {
+        opcodes
+            .into_iter()
+            .map(|op| format!("{:?}", op as char))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    println!("Instructions: {}", show(instructions.opcodes()));
+    let extended = instructions
+        .extended_names()
+        .into_iter()
+        .map(|name| format!("{}{}", instructions.syntax().extended as char, name as char))
+        .collect::<Vec<_>>();
+    println!("Extended: {}", extended.join(" "));
+    println!("Literals: {}", show(instructions.literal_prefixes()));
+}
+
+/// Exit because the file at `path` can't be used
+fn fail(path: &Path, err: io::Error) -> ! {
+    eprintln!("ERROR: Can't open {}: {}", path.display(), err);
+    process::exit(1)
+}
+
+/// Print the token statistics of all .chase files in `dir` and its subdirectories
+fn analyze_corpus(dir: &Path) {
+    let paths = analysis::find_programs(dir).unwrap_or_else(|err| {
+        eprintln!("ERROR: Can't read {}: {}", dir.display(), err);
+        process::exit(1)
+    });
+
+    let programs = paths
+        .iter()
+        .map(|path| fs::read(path).unwrap_or_else(|err| fail(path, err)));
+    print!(
+        "{}",
+        analysis::analyze_corpus(&InstructionSet::standard(), programs)
+    );
+}
+
+/// Print the smallest program found which halts the same way as the program at `path`
+#[cfg(feature = "devtools")]
+fn minimize(path: &Path) {
+    use chasement::{minimize, Data};
+
+    const MAX_STEPS: u64 = 1_000_000;
+
+    let program = fs::read(path).unwrap_or_else(|err| fail(path, err));
+    let mut instructions = InstructionSet::standard();
+    // Keep the output clean, candidates must not print or wait for input
+    instructions.insert(b'p', |ctx| {
+        ctx.pop();
+        Ok(())
+    });
+    instructions.insert(b'h', |_| Ok(()));
+    instructions.insert(b',', |ctx| {
+        ctx.push(Data::Char('\0'));
+        Ok(())
+    });
+
+    let original = minimize::run_report(&instructions, &program, MAX_STEPS);
+    let minimized = minimize::minimize(&instructions, &program, MAX_STEPS, |report| {
+        report.same_halt_kind(&original)
+    });
+    println!("{}", String::from_utf8_lossy(&minimized));
+}
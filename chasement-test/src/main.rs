@@ -0,0 +1,105 @@
+//! Runs .chase programs and compares their output to the output they expect.
+//!
+//! The expected output is written in comments of the program itself, every line like
+//! `# expect: 42` is one line of it. The comment ends at the newline, so the expected text
+//! can't contain a '#'. Trailing whitespace of the output is ignored, so a program printing
+//! "1 2 3 " passes `# expect: 1 2 3`. Programs without an expect comment are skipped.
+
+use std::{env, fs, path::PathBuf, process};
+
+use chasement::{analysis, InstructionSet, RunOutcome, Vm};
+
+/// Programs which run longer than this fail, so an endless loop can't hang the test run
+const MAX_STEPS: u64 = 10_000_000;
+
+const EXPECT_PREFIX: &str = "# expect:";
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.is_empty() {
+        eprintln!("Usage: chasement-test PATH...\n\nPATH can be a .chase file or a directory");
+        process::exit(1)
+    }
+
+    let mut paths = Vec::new();
+    for arg in args {
+        let path = PathBuf::from(arg);
+        if path.is_dir() {
+            match analysis::find_programs(&path) {
+                Ok(found) => paths.extend(found),
+                Err(err) => {
+                    eprintln!("ERROR: Can't read {}: {}", path.display(), err);
+                    process::exit(1)
+                }
+            }
+        } else {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let (mut passed, mut failed) = (0, 0);
+    for path in &paths {
+        let program = match fs::read(path) {
+            Ok(program) => program,
+            Err(err) => {
+                println!("FAILED {}: {}", path.display(), err);
+                failed += 1;
+                continue;
+            }
+        };
+        let expected = match expected_output(&program) {
+            Some(expected) => expected,
+            None => continue,
+        };
+
+        match run(&program) {
+            Ok(output) if output.trim_end() == expected.trim_end() => {
+                println!("ok     {}", path.display());
+                passed += 1;
+            }
+            Ok(output) => {
+                println!("FAILED {}", path.display());
+                println!("    expected: {:?}", expected.trim_end());
+                println!("    found:    {:?}", output.trim_end());
+                failed += 1;
+            }
+            Err(err) => {
+                println!("FAILED {}: {}", path.display(), err);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("\n{} passed, {} failed", passed, failed);
+    if failed > 0 {
+        process::exit(1)
+    }
+}
+
+/// Get the expected output written in the expect comments of `program`, `None` if it has none
+fn expected_output(program: &[u8]) -> Option<String> {
+    let lines = String::from_utf8_lossy(program)
+        .lines()
+        .filter_map(|line| line.trim_start().strip_prefix(EXPECT_PREFIX))
+        .map(|expected| expected.strip_prefix(' ').unwrap_or(expected).to_owned())
+        .collect::<Vec<_>>();
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+/// Run `program` without input and return everything it printed
+fn run(program: &[u8]) -> Result<String, String> {
+    let mut output = Vec::new();
+    let mut vm =
+        Vm::new(InstructionSet::standard(), program).with_io_sandbox(Vec::new(), &mut output);
+    match vm.run_with_limit(MAX_STEPS) {
+        Ok(RunOutcome::Completed) => (),
+        Ok(RunOutcome::LimitReached) => {
+            return Err(format!("Still running after {} instructions", MAX_STEPS))
+        }
+        Err(err) => return Err(err.to_string()),
+    }
+    drop(vm);
+
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
@@ -0,0 +1,189 @@
+//! Static statistics over many programs, used to find out which instructions and instruction
+//! pairs are common enough to deserve a combined instruction.
+//!
+//! Programs are split into tokens like the Vm sees them: a literal like `123` or `'a` is one
+//! token counted under its prefix, comments are skipped completely.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    fs,
+    hash::Hash,
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{instructions::comment_end, InstructionSet, Opcode, ProgramStorage};
+
+/// Token counts of a corpus of programs
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CorpusReport {
+    /// Number of analyzed programs
+    pub programs: usize,
+    /// Number of tokens in all programs
+    pub tokens: u64,
+    /// How often every opcode starts a token
+    pub opcodes: HashMap<Opcode, u64>,
+    /// How often every pair of directly following tokens appears, by their first opcodes
+    pub digrams: HashMap<(Opcode, Opcode), u64>,
+    /// How often a literal with a length in bytes appears, including the prefix
+    pub literal_sizes: HashMap<usize, u64>,
+}
+
+impl CorpusReport {
+    /// Get the opcode counts, the most frequent opcode comes first
+    pub fn sorted_opcodes(&self) -> Vec<(Opcode, u64)> {
+        sorted_by_count(&self.opcodes)
+    }
+
+    /// Get the digram counts, the most frequent digram comes first
+    pub fn sorted_digrams(&self) -> Vec<((Opcode, Opcode), u64)> {
+        sorted_by_count(&self.digrams)
+    }
+
+    /// Get the literal size counts, the most frequent size comes first
+    pub fn sorted_literal_sizes(&self) -> Vec<(usize, u64)> {
+        sorted_by_count(&self.literal_sizes)
+    }
+
+    fn add_program<P: ProgramStorage>(&mut self, instructions: &InstructionSet<P>, program: &P) {
+        let comment = instructions.syntax().comment;
+        let mut prev = None;
+        let mut pc = 0;
+
+        self.programs += 1;
+        while let Some(op) = program.opcode_at(pc) {
+            if op == comment {
+                pc = comment_end(program, pc, comment) + 1;
+                continue;
+            }
+
+            let len = instructions.token_len(program, pc);
+            if instructions.get_literal(&op).is_some() {
+                *self.literal_sizes.entry(len).or_default() += 1;
+            }
+            *self.opcodes.entry(op).or_default() += 1;
+            if let Some(prev) = prev {
+                *self.digrams.entry((prev, op)).or_default() += 1;
+            }
+
+            self.tokens += 1;
+            prev = Some(op);
+            pc += len;
+        }
+    }
+}
+
+impl Display for CorpusReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Programs: {}, tokens: {}", self.programs, self.tokens)?;
+        writeln!(f, "Opcodes:")?;
+        for (op, count) in self.sorted_opcodes() {
+            writeln!(f, "    {:?} {}", op as char, count)?;
+        }
+        writeln!(f, "Digrams:")?;
+        for ((first, second), count) in self.sorted_digrams() {
+            writeln!(f, "    {:?} {:?} {}", first as char, second as char, count)?;
+        }
+        writeln!(f, "Literal sizes:")?;
+        for (size, count) in self.sorted_literal_sizes() {
+            writeln!(f, "    {} bytes {}", size, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Count the tokens of all `programs`, literals are recognized with the literal prefixes of
+/// `instructions`
+pub fn analyze_corpus<P: ProgramStorage>(
+    instructions: &InstructionSet<P>,
+    programs: impl Iterator<Item = P>,
+) -> CorpusReport {
+    let mut report = CorpusReport::default();
+    for program in programs {
+        report.add_program(instructions, &program);
+    }
+    report
+}
+
+/// Find all .chase files in `dir` and its subdirectories, sorted by path
+pub fn find_programs(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fn collect(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                collect(&path, paths)?;
+            } else if path.extension().is_some_and(|ext| ext == "chase") {
+                paths.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    let mut paths = Vec::new();
+    collect(dir, &mut paths)?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Sort the entries by descending count, equal counts by ascending key
+fn sorted_by_count<K: Copy + Ord + Hash>(counts: &HashMap<K, u64>) -> Vec<(K, u64)> {
+    let mut sorted = counts.iter().map(|(k, c)| (*k, *c)).collect::<Vec<_>>();
+    sorted.sort_unstable_by(|(k1, c1), (k2, c2)| c2.cmp(c1).then(k1.cmp(k2)));
+    sorted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::analyze_corpus;
+    use crate::InstructionSet;
+
+    #[test]
+    fn counts_tokens() {
+        let corpus = ["123 45+p", "'a p# 99+\n\"ab\"p12+"];
+        let report = analyze_corpus(&InstructionSet::standard(), corpus.into_iter());
+
+        assert_eq!(report.programs, 2);
+        // Literals are one token each, the comment and the newline ending it are no tokens
+        assert_eq!(report.tokens, 12);
+        assert_eq!(
+            report.sorted_opcodes(),
+            vec![
+                (b'p', 3),
+                (b' ', 2),
+                (b'+', 2),
+                (b'1', 2),
+                (b'"', 1),
+                (b'\'', 1),
+                (b'4', 1),
+            ]
+        );
+        // The comment between 'p' and '"' doesn't split them
+        assert_eq!(
+            report.sorted_digrams(),
+            vec![
+                ((b' ', b'4'), 1),
+                ((b' ', b'p'), 1),
+                ((b'"', b'p'), 1),
+                ((b'\'', b' '), 1),
+                ((b'+', b'p'), 1),
+                ((b'1', b' '), 1),
+                ((b'1', b'+'), 1),
+                ((b'4', b'+'), 1),
+                ((b'p', b'"'), 1),
+                ((b'p', b'1'), 1),
+            ]
+        );
+        assert_eq!(report.sorted_literal_sizes(), vec![(2, 3), (3, 1), (4, 1)]);
+    }
+
+    #[test]
+    fn empty_corpus() {
+        let report = analyze_corpus(&InstructionSet::<&str>::standard(), std::iter::empty());
+        assert_eq!(report, Default::default());
+        assert_eq!(
+            report.to_string(),
+            "Programs: 0, tokens: 0\nOpcodes:\nDigrams:\nLiteral sizes:\n"
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! Per instruction policies of the host.
+//!
+//! An [`Authorizer`] installed with [`crate::Vm::set_authorizer`] is asked before every
+//! instruction if it may run. It sees the whole context, so it can enforce policies like
+//! "print at most 1 KB" by inspecting the value a print would pop.
+
+use crate::{Context, Opcode, ProgramStorage};
+
+/// The answer of an [`Authorizer`] for one instruction
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Run the instruction
+    Allow,
+    /// Don't run the instruction and fail with `VmError::Denied` containing the reason
+    Deny(String),
+    /// Don't run the instruction and end the program without an error
+    Halt,
+}
+
+/// Decides if an instruction may be executed
+pub trait Authorizer<P: ProgramStorage> {
+    /// Called before the instruction `opcode` at the program counter of `ctx` is executed
+    fn allow(&mut self, ctx: &Context<P>, opcode: Opcode) -> Decision;
+}
+
+impl<P: ProgramStorage, F: FnMut(&Context<P>, Opcode) -> Decision> Authorizer<P> for F {
+    fn allow(&mut self, ctx: &Context<P>, opcode: Opcode) -> Decision {
+        self(ctx, opcode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Authorizer, Decision};
+    use crate::{Context, InstructionSet, Opcode, ProgramStorage, Vm, VmError, VmResult};
+
+    /// Allows printing at most `left` bytes
+    struct PrintBudget {
+        left: usize,
+    }
+
+    impl<P: ProgramStorage> Authorizer<P> for PrintBudget {
+        fn allow(&mut self, ctx: &Context<P>, opcode: Opcode) -> Decision {
+            if opcode != b'p' {
+                return Decision::Allow;
+            }
+            let len = ctx.top().map_or(0, |val| val.to_string().len());
+            match self.left.checked_sub(len) {
+                Some(left) => {
+                    self.left = left;
+                    Decision::Allow
+                }
+                None => Decision::Deny(format!(
+                    "print budget exceeded by {} bytes",
+                    len - self.left
+                )),
+            }
+        }
+    }
+
+    /// Run `program` with `authorizer` and get the result and everything that was printed
+    fn run_authorized<'p>(
+        program: &'p str,
+        authorizer: impl Authorizer<&'p str> + 'p,
+    ) -> (VmResult, String) {
+        let mut output = Vec::new();
+        let res = {
+            let mut vm = Vm::new(InstructionSet::standard(), program)
+                .with_io_sandbox(Vec::new(), &mut output);
+            vm.set_authorizer(authorizer);
+            let res = vm.run();
+            vm.get_context().flush_output().unwrap();
+            res
+        };
+        (res, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn print_budget() {
+        let program = "\"abc\"p\"abc\"p\"abc\"p\"abc\"p";
+        let (res, output) = run_authorized(program, PrintBudget { left: 8 });
+        assert_eq!(output, "abcabc");
+        assert_eq!(
+            res,
+            Err(VmError::Denied {
+                reason: "print budget exceeded by 1 bytes".into(),
+                pc: 17,
+            })
+        );
+
+        // Exactly the budget is fine
+        let (res, output) = run_authorized(program, PrintBudget { left: 12 });
+        assert_eq!((res, output.as_str()), (Ok(()), "abcabcabcabc"));
+    }
+
+    #[test]
+    fn halt() {
+        let halt_on_print = |_: &Context<&str>, opcode| match opcode {
+            b'p' => Decision::Halt,
+            _ => Decision::Allow,
+        };
+        let (res, output) = run_authorized("1 2p3p", halt_on_print);
+        assert_eq!((res, output.as_str()), (Ok(()), ""));
+    }
+}
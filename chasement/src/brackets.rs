@@ -0,0 +1,304 @@
+//! Static matching of the loop (`[` `]`) and skip (`(` `)`) brackets of a program.
+//!
+//! The table is built in one iterative pass with an explicit stack, so arbitrarily deep nesting
+//! can't overflow the call stack. Like the scanning instructions `]` and `(` every bracket byte
+//! is counted, even inside comments or after a quote.
+
+use std::fmt::{self, Display, Formatter};
+
+use crate::{instructions::Syntax, ProgramStorage};
+
+/// The kind of a bracket pair
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BracketKind {
+    /// `[` and `]` by default
+    Loop,
+    /// `(` and `)` by default
+    Skip,
+}
+
+/// A problem found while matching the brackets of a program
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BracketError {
+    /// An opening bracket at `pc` is never closed
+    Unclosed { kind: BracketKind, pc: usize },
+    /// A closing bracket at `pc` has no opening bracket
+    Unopened { kind: BracketKind, pc: usize },
+    /// The pair opened at `open` is closed at `close` while the pair opened at `inner` is still
+    /// open inside of it, e.g. `[(])`
+    Crossing {
+        open: usize,
+        inner: usize,
+        close: usize,
+    },
+}
+
+impl Display for BracketError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unclosed { kind, pc } => write!(f, "Unclosed {:?} bracket at {}", kind, pc),
+            Self::Unopened { kind, pc } => write!(f, "Unopened {:?} bracket at {}", kind, pc),
+            Self::Crossing { open, inner, close } => write!(
+                f,
+                "Bracket opened at {} is closed at {} while the one at {} is still open",
+                open, close, inner
+            ),
+        }
+    }
+}
+
+impl BracketError {
+    /// Get the pc of the bracket at which the error was found
+    pub fn pc(&self) -> usize {
+        match self {
+            Self::Unclosed { pc, .. } | Self::Unopened { pc, .. } => *pc,
+            Self::Crossing { close, .. } => *close,
+        }
+    }
+}
+
+impl std::error::Error for BracketError {}
+
+/// The matching bracket for every bracket of a program
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BracketTable {
+    /// Sorted pairs of (bracket pc, matching bracket pc), containing both directions
+    pairs: Vec<(usize, usize)>,
+}
+
+impl BracketTable {
+    /// Match all brackets of `program`, using the bracket opcodes of `syntax`
+    pub fn build<P: ProgramStorage>(program: &P, syntax: &Syntax) -> Result<Self, BracketError> {
+        let mut open = Vec::<(BracketKind, usize)>::new();
+        let mut pairs = Vec::new();
+        let mut pc = 0;
+
+        while let Some(op) = program.opcode_at(pc) {
+            let (kind, opening) = if op == syntax.loop_open {
+                (BracketKind::Loop, true)
+            } else if op == syntax.loop_close {
+                (BracketKind::Loop, false)
+            } else if op == syntax.skip_open {
+                (BracketKind::Skip, true)
+            } else if op == syntax.skip_close {
+                (BracketKind::Skip, false)
+            } else {
+                pc += 1;
+                continue;
+            };
+
+            if opening {
+                open.push((kind, pc));
+            } else {
+                match open.pop() {
+                    Some((open_kind, open_pc)) if open_kind == kind => {
+                        pairs.push((open_pc, pc));
+                        pairs.push((pc, open_pc));
+                    }
+                    Some((_, inner)) => {
+                        return Err(match open.iter().rev().find(|(k, _)| *k == kind) {
+                            Some((_, open_pc)) => BracketError::Crossing {
+                                open: *open_pc,
+                                inner,
+                                close: pc,
+                            },
+                            None => BracketError::Unopened { kind, pc },
+                        })
+                    }
+                    None => return Err(BracketError::Unopened { kind, pc }),
+                }
+            }
+
+            pc += 1;
+        }
+
+        if let Some((kind, pc)) = open.pop() {
+            return Err(BracketError::Unclosed { kind, pc });
+        }
+
+        pairs.sort_unstable();
+        Ok(Self { pairs })
+    }
+
+    /// Get the pc of the bracket matching the bracket at `pc`
+    pub fn matching(&self, pc: usize) -> Option<usize> {
+        self.pairs
+            .binary_search_by_key(&pc, |(from, _)| *from)
+            .ok()
+            .map(|idx| self.pairs[idx].1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BracketError, BracketKind, BracketTable};
+    use crate::{instructions::Instruction, InstructionSet, Syntax, Vm};
+
+    const DEPTH: usize = 100_000;
+
+    fn build(program: &[u8]) -> Result<BracketTable, BracketError> {
+        BracketTable::build(&program, &Syntax::default())
+    }
+
+    /// Get the scanning instruction registered for `opcode`
+    fn scanning(opcode: u8) -> Instruction<Vec<u8>> {
+        InstructionSet::standard().get(&opcode).unwrap()
+    }
+
+    #[test]
+    fn matching() {
+        let table = build(b"1[2(3)4]()").unwrap();
+        assert_eq!(table.matching(1), Some(7));
+        assert_eq!(table.matching(7), Some(1));
+        assert_eq!(table.matching(3), Some(5));
+        assert_eq!(table.matching(8), Some(9));
+        assert_eq!(table.matching(0), None);
+    }
+
+    #[test]
+    fn unmatched() {
+        use BracketKind::*;
+        assert_eq!(
+            build(b"[[]"),
+            Err(BracketError::Unclosed { kind: Loop, pc: 0 })
+        );
+        assert_eq!(
+            build(b"())"),
+            Err(BracketError::Unopened { kind: Skip, pc: 2 })
+        );
+        assert_eq!(
+            build(b"[)"),
+            Err(BracketError::Unopened { kind: Skip, pc: 1 })
+        );
+    }
+
+    #[test]
+    fn crossing() {
+        let crossing = |open, inner, close| Err(BracketError::Crossing { open, inner, close });
+        assert_eq!(build(b"[(])"), crossing(0, 1, 2));
+        assert_eq!(build(b"([)]"), crossing(0, 1, 2));
+        assert_eq!(build(b"[[(]])"), crossing(1, 2, 3));
+
+        // Deep crossing pairs are found without recursion
+        let mut program = vec![b'['; DEPTH];
+        program.extend(b"(]");
+        assert_eq!(build(&program), crossing(DEPTH - 1, DEPTH, DEPTH + 1));
+    }
+
+    #[test]
+    fn deep_nesting() {
+        for (open, close) in [(b'[', b']'), (b'(', b')')] {
+            let mut program = vec![open; DEPTH];
+            program.extend(vec![close; DEPTH]);
+            let table = build(&program).unwrap();
+            assert_eq!(table.matching(0), Some(2 * DEPTH - 1));
+            assert_eq!(table.matching(DEPTH - 1), Some(DEPTH));
+        }
+    }
+
+    #[test]
+    fn deep_nesting_scanning() {
+        let (jump_back, paren_open) = (scanning(b']'), scanning(b'('));
+        let mut program = vec![b'['; DEPTH];
+        program.extend(vec![b']'; DEPTH]);
+        let mut vm = Vm::new(InstructionSet::standard(), program.clone());
+        let ctx = vm.get_context_mut();
+        ctx.set_pc(2 * DEPTH - 1);
+        jump_back(ctx).unwrap();
+        // The Vm continues at the '[' after the instruction
+        assert_eq!(ctx.get_pc().wrapping_add(1), 0);
+
+        let mut program = vec![b'('; DEPTH];
+        program.extend(vec![b')'; DEPTH]);
+        let mut vm = Vm::new(InstructionSet::standard(), program.clone());
+        let ctx = vm.get_context_mut();
+        paren_open(ctx).unwrap();
+        assert_eq!(ctx.get_pc(), 2 * DEPTH - 1);
+    }
+
+    /// Generate a random program of well-formed brackets of both kinds and other opcodes
+    fn bracket_soup(seed: &mut u64, len: usize) -> Vec<u8> {
+        let mut next = || {
+            // Linear congruential generator of Knuth's MMIX
+            *seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (*seed >> 33) as usize
+        };
+
+        let mut program = Vec::new();
+        let mut open = Vec::new();
+        while program.len() < len {
+            match next() % 5 {
+                0 => {
+                    program.push(b'[');
+                    open.push(b']');
+                }
+                1 => {
+                    program.push(b'(');
+                    open.push(b')');
+                }
+                2 | 3 if !open.is_empty() => program.push(open.pop().unwrap()),
+                _ => program.push(b"1 p"[next() % 3]),
+            }
+        }
+        program.extend(open.iter().rev());
+        program
+    }
+
+    #[test]
+    fn table_agrees_with_scanning() {
+        let (jump_back, paren_open) = (scanning(b']'), scanning(b'('));
+        let mut seed = 0x5eed;
+        for _ in 0..200 {
+            let program = bracket_soup(&mut seed, 300);
+            let table = build(&program).unwrap();
+            let mut vm = Vm::new(InstructionSet::standard(), program.clone());
+            let ctx = vm.get_context_mut();
+
+            for (pc, op) in program.iter().enumerate() {
+                ctx.set_pc(pc);
+                match op {
+                    b']' => {
+                        jump_back(ctx).unwrap();
+                        assert_eq!(Some(ctx.get_pc().wrapping_add(1)), table.matching(pc));
+                    }
+                    b'(' => {
+                        paren_open(ctx).unwrap();
+                        assert_eq!(Some(ctx.get_pc()), table.matching(pc));
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn vm_checks_brackets() {
+        let run = |program: &str| {
+            let mut output = Vec::new();
+            let mut vm = Vm::new(InstructionSet::standard(), program)
+                .with_io_sandbox(Vec::new(), &mut output);
+            vm.enable_bracket_checks();
+            let res = vm.run();
+            drop(vm);
+            (res, output)
+        };
+
+        let (res, output) = run("1p[(])");
+        assert_eq!(
+            res,
+            Err(crate::VmError::Brackets {
+                error: BracketError::Crossing {
+                    open: 2,
+                    inner: 3,
+                    close: 4
+                },
+                pc: 4
+            })
+        );
+        // Nothing was executed
+        assert!(output.is_empty());
+        assert_eq!(run("1p(2p)3p").0, Ok(()));
+    }
+}
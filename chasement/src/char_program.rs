@@ -0,0 +1,179 @@
+//! Programs indexed by chars instead of bytes.
+//!
+//! A [`CharProgram`] is decoded from UTF-8 up front, so every program counter counts chars and
+//! non-ASCII symbols like '∑' can be used as instructions. Instructions are still keyed by one
+//! byte opcode, a non-ASCII char is executed as the opcode it is aliased to. Every scanning
+//! instruction works unchanged because each char is exactly one opcode, and literals read the
+//! chars themselves, so `"héllo"` is a Str literal of five chars.
+
+use std::collections::HashMap;
+
+use crate::{Opcode, ProgramStorage};
+
+/// The opcode of non-ASCII chars without an alias. No builtin instruction uses it, so executing
+/// such a char fails while it can still be part of literals and comments.
+pub const UNMAPPED_OPCODE: Opcode = 0xFF;
+
+/// A program where every char is one opcode
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CharProgram {
+    chars: Vec<char>,
+    opcodes: Vec<Opcode>,
+}
+
+impl CharProgram {
+    /// Decode `text` into a program. Chars are mapped to opcodes by `aliases`, other ASCII
+    /// chars are their own opcode and every other char is [`UNMAPPED_OPCODE`].
+    pub fn new(text: &str, aliases: &HashMap<char, Opcode>) -> Self {
+        let chars = text.chars().collect::<Vec<_>>();
+        let opcodes = chars
+            .iter()
+            .map(|ch| match aliases.get(ch) {
+                Some(op) => *op,
+                None if ch.is_ascii() => *ch as Opcode,
+                None => UNMAPPED_OPCODE,
+            })
+            .collect();
+
+        Self { chars, opcodes }
+    }
+
+    /// Get the number of chars of the program
+    pub fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+}
+
+impl ProgramStorage for CharProgram {
+    fn opcode_at(&self, idx: usize) -> Option<Opcode> {
+        self.opcodes.get(idx).copied()
+    }
+
+    unsafe fn opcode_at_unchecked(&self, idx: usize) -> Opcode {
+        *self.opcodes.get_unchecked(idx)
+    }
+
+    fn copy_range(&self, start: usize, len: usize) -> Vec<Opcode> {
+        let end = start.saturating_add(len).min(self.opcodes.len());
+        self.opcodes.get(start..end).unwrap_or_default().to_vec()
+    }
+
+    /// Every char is one opcode
+    fn char_at(&self, idx: usize) -> Option<(char, usize)> {
+        self.chars.get(idx).map(|c| (*c, 1))
+    }
+}
+
+impl ProgramStorage for &CharProgram {
+    fn opcode_at(&self, idx: usize) -> Option<Opcode> {
+        (*self).opcode_at(idx)
+    }
+
+    unsafe fn opcode_at_unchecked(&self, idx: usize) -> Opcode {
+        (*self).opcode_at_unchecked(idx)
+    }
+
+    fn copy_range(&self, start: usize, len: usize) -> Vec<Opcode> {
+        (*self).copy_range(start, len)
+    }
+
+    fn char_at(&self, idx: usize) -> Option<(char, usize)> {
+        (*self).char_at(idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{CharProgram, UNMAPPED_OPCODE};
+    use crate::{test_util, Context, Data, InstructionSet, ProgramStorage, Vm, VmError, VmResult};
+
+    /// ('∑') Pop every Int and push their sum
+    fn sum<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+        let mut sum = 0;
+        while let Some(&Data::Int(i)) = ctx.top() {
+            sum += i;
+            ctx.pop();
+        }
+        ctx.push(Data::Int(sum));
+        Ok(())
+    }
+
+    fn run(text: &str) -> (VmResult, Vec<Data>, String) {
+        let mut instructions = InstructionSet::standard();
+        instructions.insert(0x80, sum);
+        let program = CharProgram::new(text, &HashMap::from([('∑', 0x80)]));
+
+        let mut output = Vec::new();
+        let mut vm = Vm::new(instructions, &program).with_io_sandbox(Vec::new(), &mut output);
+        let res = vm.run();
+        let stack = vm.get_context_mut().take_stack();
+        drop(vm);
+        (res, stack, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn aliased_instruction() {
+        let (res, stack, _) = run("1 2 3 4∑");
+        assert!(res.is_ok());
+        assert_eq!(stack, vec![Data::Int(10)]);
+    }
+
+    #[test]
+    fn pcs_count_chars() {
+        let program = CharProgram::new("é∑[", &HashMap::from([('∑', 0x80)]));
+        assert_eq!(program.len(), 3);
+        assert_eq!(program.opcode_at(0), Some(UNMAPPED_OPCODE));
+        assert_eq!(program.opcode_at(1), Some(0x80));
+        assert_eq!(program.opcode_at(2), Some(b'['));
+        assert_eq!(program.char_at(1), Some(('∑', 1)));
+
+        let (_, stack, _) = run("\"é\"[");
+        assert_eq!(stack, vec![Data::Str("é".into()), Data::Int(3)]);
+    }
+
+    #[test]
+    fn literals_read_chars() {
+        let (res, _, output) = run("\"héllo\"p");
+        assert!(res.is_ok());
+        assert_eq!(output, "héllo");
+
+        let (_, stack, _) = run("'∑ '\\n \"a\\\"∑\"");
+        assert_eq!(
+            stack,
+            vec![Data::Char('∑'), Data::Char('\n'), Data::Str("a\"∑".into())]
+        );
+    }
+
+    #[test]
+    fn unmapped_chars_are_no_instructions() {
+        let (res, stack, _) = run("1 é");
+        assert!(matches!(
+            res,
+            Err(VmError::UnknownOpcode {
+                opcode: UNMAPPED_OPCODE,
+                pc: 2
+            })
+        ));
+        assert_eq!(stack, vec![Data::Int(1)]);
+        // Comments can contain them
+        assert!(run("#é#1").0.is_ok());
+    }
+
+    #[test]
+    fn byte_programs_decode_literals_as_utf8() {
+        assert_eq!(
+            test_util::stack("'é '∑ \"∑é\""),
+            vec![Data::Char('é'), Data::Char('∑'), Data::Str("∑é".into())]
+        );
+        assert!(matches!(
+            Vm::new(InstructionSet::standard(), b"'\xff" as &[u8]).run(),
+            Err(VmError::Instruction { .. })
+        ));
+    }
+}
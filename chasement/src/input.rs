@@ -0,0 +1,40 @@
+//! Where the reading instructions get their input from.
+
+use std::{
+    cell::RefCell,
+    fmt::{self, Debug, Formatter},
+    io::{self, Read},
+    rc::Rc,
+};
+
+/// Input source of a Context
+#[derive(Clone)]
+pub(crate) struct Input<'a> {
+    /// Shared with all clones of the Context, so they read from the same source
+    pub(crate) reader: Rc<RefCell<dyn Read + 'a>>,
+}
+
+impl Input<'_> {
+    /// Read the next byte, `None` at the end of the input
+    pub(crate) fn read_byte(&self) -> io::Result<Option<u8>> {
+        let mut buf = [0; 1];
+        match self.reader.borrow_mut().read(&mut buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+}
+
+impl Default for Input<'_> {
+    fn default() -> Self {
+        Self {
+            reader: Rc::new(RefCell::new(io::stdin())),
+        }
+    }
+}
+
+impl Debug for Input<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Input").finish_non_exhaustive()
+    }
+}
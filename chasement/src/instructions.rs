@@ -1,19 +1,85 @@
+mod arithmetic;
 mod base;
+mod convert;
+mod list;
+mod string;
 
-use std::collections::HashMap;
+pub(crate) use base::comment_end;
 
-use crate::{Context, Opcode, ProgramStorage};
+use crate::{
+    literal::{self, LiteralScannerFn, Literals},
+    Context, Data, Opcode, ProgramStorage, VmError, VmResult, LANGUAGE_VERSION,
+};
 
-pub type Instruction<P> = fn(&mut Context<P>);
-pub type InstructionSetInner<P> =
-    HashMap<Opcode, Instruction<P>, nohash::BuildNoHashHasher<Opcode>>;
+pub type Instruction<P> = fn(&mut Context<P>) -> VmResult;
+/// The instructions indexed by their opcode, `None` for opcodes without an instruction
+pub type InstructionSetInner<P> = [Option<Instruction<P>>; 256];
 
 #[derive(Clone)]
-pub struct InstructionSet<P: ProgramStorage>(InstructionSetInner<P>);
+pub struct InstructionSet<P: ProgramStorage> {
+    instructions: InstructionSetInner<P>,
+    /// Extended instructions indexed by the opcode following `Syntax::extended`
+    extended: InstructionSetInner<P>,
+    literals: Literals,
+    syntax: Syntax,
+    language_version: (u8, u8),
+}
+
+/// The opcodes of the structural instructions, which scan through the program
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Syntax {
+    /// Starts a comment that ends at the next comment opcode or newline, '#' by default.
+    /// Followed by '{' it starts a nested block comment ending at the comment opcode and '}'.
+    pub comment: Opcode,
+    /// Pushes the next byte as char, '\'' by default
+    pub quote: Opcode,
+    /// Starts and ends a Str literal, '"' by default
+    pub string: Opcode,
+    /// Pushes the current pc, '[' by default
+    pub loop_open: Opcode,
+    /// Jumps back to the matching `loop_open`, ']' by default
+    pub loop_close: Opcode,
+    /// Jumps ahead to the matching `skip_close`, '(' by default
+    pub skip_open: Opcode,
+    /// End of a skipped block, ')' by default
+    pub skip_close: Opcode,
+    /// Followed by one more opcode, the name, it runs the extended instruction registered for
+    /// that name, '?' by default. This is how instructions without an opcode of their own are
+    /// written, e.g. `?<`. Instructions or literals registered for this opcode are never run.
+    pub extended: Opcode,
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Self {
+            comment: b'#',
+            quote: b'\'',
+            string: b'"',
+            loop_open: b'[',
+            loop_close: b']',
+            skip_open: b'(',
+            skip_close: b')',
+            extended: b'?',
+        }
+    }
+}
+
+impl<P: ProgramStorage> Default for InstructionSet<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl<P: ProgramStorage> InstructionSet<P> {
     pub fn new() -> Self {
-        Self(Default::default())
+        Self {
+            instructions: [None; 256],
+            extended: [None; 256],
+            literals: Default::default(),
+            syntax: Default::default(),
+            language_version: LANGUAGE_VERSION,
+        }
     }
 
     pub fn new_with<F: FnOnce(&mut Self)>(add_instructions: F) -> Self {
@@ -22,29 +88,410 @@ impl<P: ProgramStorage> InstructionSet<P> {
         me
     }
 
+    /// Create an instruction set with every builtin instruction group and the default syntax,
+    /// the one the command line tools run programs with
+    pub fn standard() -> Self {
+        Self::new_with(|me| {
+            me.with_base_instructions(Syntax::default())
+                .with_arithmetic_instructions()
+                .with_list_instructions()
+                .with_string_instructions()
+                .with_conversion_instructions();
+        })
+    }
+
     pub fn inner_mut(&mut self) -> &mut InstructionSetInner<P> {
-        &mut self.0
+        &mut self.instructions
     }
 
     pub fn insert(&mut self, opcode: u8, instruction: Instruction<P>) {
-        self.0.insert(opcode, instruction);
+        self.literals.remove(&opcode);
+        self.instructions[opcode as usize] = Some(instruction);
+    }
+
+    /// Register a literal started by `prefix`, replacing any instruction for that opcode.
+    /// See [`crate::literal`] for how literals are executed.
+    pub fn insert_literal(&mut self, prefix: u8, scanner: LiteralScannerFn) {
+        self.instructions[prefix as usize] = None;
+        self.literals.insert(prefix, scanner);
+    }
+
+    pub fn get_literal(&self, prefix: &u8) -> Option<LiteralScannerFn> {
+        self.literals.get(prefix).copied()
+    }
+
+    /// Register an extended instruction, which is run by the `Syntax::extended` opcode followed
+    /// by `name`. Extended instructions don't use up one of the 256 opcodes.
+    pub fn insert_extended(&mut self, name: Opcode, instruction: Instruction<P>) {
+        self.extended[name as usize] = Some(instruction);
+    }
+
+    pub fn get_extended(&self, name: &u8) -> Option<Instruction<P>> {
+        self.extended[*name as usize]
+    }
+
+    pub fn remove_extended(&mut self, name: &u8) -> Option<Instruction<P>> {
+        self.extended[*name as usize].take()
+    }
+
+    /// Get the length in bytes of the token at `pc`, which is more than 1 for literals and
+    /// extended instructions
+    pub fn token_len(&self, program: &P, pc: usize) -> usize {
+        literal::token_len(&self.literals, &self.syntax, program, pc)
+    }
+
+    /// Get all opcodes with an instruction in ascending order, literal prefixes are not included
+    pub fn opcodes(&self) -> Vec<Opcode> {
+        (0..=Opcode::MAX)
+            .filter(|opcode| self.instructions[*opcode as usize].is_some())
+            .collect()
+    }
+
+    /// Get the names of all extended instructions in ascending order
+    pub fn extended_names(&self) -> Vec<Opcode> {
+        (0..=Opcode::MAX)
+            .filter(|name| self.extended[*name as usize].is_some())
+            .collect()
+    }
+
+    /// Get a fingerprint of the opcode mapping, a hash of the language version, the syntax, the
+    /// opcodes with an instruction, the extended names and the literal prefixes. It is the same
+    /// in every build, but replacing an instruction by another one doesn't change it.
+    pub fn fingerprint(&self) -> u64 {
+        let Syntax {
+            comment,
+            quote,
+            string,
+            loop_open,
+            loop_close,
+            skip_open,
+            skip_close,
+            extended,
+        } = self.syntax;
+        let mut bytes = vec![self.language_version.0, self.language_version.1];
+        bytes.extend([
+            comment, quote, string, loop_open, loop_close, skip_open, skip_close, extended,
+        ]);
+        for opcodes in [
+            self.opcodes(),
+            self.extended_names(),
+            self.literal_prefixes(),
+        ] {
+            // The length separates the lists, so moving an opcode to another list is noticed
+            bytes.extend((opcodes.len() as u16).to_le_bytes());
+            bytes.extend(opcodes);
+        }
+
+        // FNV-1a, unlike the hashers of std it is stable across builds and Rust versions
+        bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    /// Get all literal prefixes in ascending order
+    pub fn literal_prefixes(&self) -> Vec<Opcode> {
+        let mut prefixes = self.literals.keys().copied().collect::<Vec<_>>();
+        prefixes.sort_unstable();
+        prefixes
+    }
+
+    pub(crate) fn literals(&self) -> &Literals {
+        &self.literals
+    }
+
+    pub fn get(&self, opcode: &u8) -> Option<Instruction<P>> {
+        self.instructions[*opcode as usize]
+    }
+
+    /// Look up the instructions of all `opcodes`, the result has the same order as `opcodes`
+    pub fn get_many(&self, opcodes: &[u8]) -> Vec<Option<Instruction<P>>> {
+        opcodes.iter().map(|opcode| self.get(opcode)).collect()
+    }
+
+    pub fn remove(&mut self, opcode: &u8) -> Option<Instruction<P>> {
+        self.literals.remove(opcode);
+        self.instructions[*opcode as usize].take()
+    }
+
+    /// Save the current opcode mapping so it can be restored with [`InstructionSet::rollback`]
+    pub fn checkpoint(&self) -> Checkpoint<P> {
+        Checkpoint(self.instructions, self.extended, self.literals.clone())
+    }
+
+    /// Restore the opcode mapping saved in `checkpoint`, discarding all changes made since
+    pub fn rollback(&mut self, checkpoint: Checkpoint<P>) {
+        self.instructions = checkpoint.0;
+        self.extended = checkpoint.1;
+        self.literals = checkpoint.2;
+    }
+
+    /// Get the syntax the structural instructions were registered with
+    pub fn syntax(&self) -> &Syntax {
+        &self.syntax
+    }
+
+    /// Get the (major, minor) language version programs are checked against, the
+    /// [`LANGUAGE_VERSION`] of the builtin instructions by default
+    pub fn language_version(&self) -> (u8, u8) {
+        self.language_version
     }
 
-    pub fn get(&self, opcode:& u8) -> Option<Instruction<P>> {
-        self.0.get(opcode).copied()
+    /// Set the language version, e.g. after adding or changing instructions
+    pub fn set_language_version(&mut self, major: u8, minor: u8) -> &mut Self {
+        self.language_version = (major, minor);
+        self
     }
 
-    pub fn with_base_instructions(&mut self) -> &mut Self {
-        base::add_base_instructions(self);
+    /// Register the base instructions, the structural ones use the opcodes of `syntax`
+    pub fn with_base_instructions(&mut self, syntax: Syntax) -> &mut Self {
+        self.syntax = syntax;
+        base::add_base_instructions(self, &syntax);
         self
     }
 
     pub fn with_arithmetic_instructions(&mut self) -> &mut Self {
+        arithmetic::add_arithmetic_instructions(self);
+        self
+    }
+
+    pub fn with_list_instructions(&mut self) -> &mut Self {
+        list::add_list_instructions(self);
+        self
+    }
+
+    pub fn with_string_instructions(&mut self) -> &mut Self {
+        string::add_string_instructions(self);
         self
     }
+
+    pub fn with_conversion_instructions(&mut self) -> &mut Self {
+        convert::add_conversion_instructions(self);
+        self
+    }
+}
+
+/// A saved opcode mapping of an [`InstructionSet`]
+#[derive(Clone)]
+pub struct Checkpoint<P: ProgramStorage>(InstructionSetInner<P>, InstructionSetInner<P>, Literals);
+
+/// Fail the current instruction with the given message
+pub fn error<T, P: ProgramStorage>(ctx: &Context<P>, err: &str) -> VmResult<T> {
+    Err(VmError::Instruction {
+        message: err.to_owned(),
+        pc: ctx.get_pc(),
+    })
 }
 
-pub fn error(err: &str) -> ! {
-    eprintln!("ERROR: {}", err);
-    std::process::exit(1)
+/// Fail the current instruction because it was called on the wrong values.
+/// `operands` are the popped values in pop order, if any of them is missing the stack
+/// underflowed, otherwise their types did not match `expected`.
+pub fn invalid_operands<T, P: ProgramStorage>(
+    ctx: &Context<P>,
+    expected: &'static str,
+    operands: &[Option<Data>],
+) -> VmResult<T> {
+    let pc = ctx.get_pc();
+    let found = operands
+        .iter()
+        .map(|op| op.as_ref().map(Data::type_name))
+        .collect::<Option<Vec<_>>>();
+
+    Err(match found {
+        Some(found) => VmError::TypeMismatch {
+            expected,
+            found: found.join(", "),
+            pc,
+        },
+        None => VmError::StackUnderflow { pc },
+    })
+}
+
+/// Fail the current instruction because there are not enough values on the stack
+pub fn stack_underflow<T, P: ProgramStorage>(ctx: &Context<P>) -> VmResult<T> {
+    Err(VmError::StackUnderflow { pc: ctx.get_pc() })
+}
+
+/// Fail the current instruction because of a division by zero
+pub fn division_by_zero<T, P: ProgramStorage>(ctx: &Context<P>) -> VmResult<T> {
+    Err(VmError::DivisionByZero { pc: ctx.get_pc() })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{Instruction, InstructionSet, Syntax};
+    use crate::{Context, Data, Vm};
+
+    #[test]
+    fn rollback() {
+        let mut instructions = InstructionSet::<&[u8]>::standard();
+        let checkpoint = instructions.checkpoint();
+
+        instructions.insert(b'<', |_| Ok(()));
+        instructions.insert_extended(b'z', |_| Ok(()));
+        instructions.remove(&b'p');
+        instructions.remove(&b'1');
+        assert!(instructions.get(&b'<').is_some());
+        assert!(instructions.get_extended(&b'z').is_some());
+
+        instructions.rollback(checkpoint);
+        assert!(instructions.get(&b'<').is_none());
+        assert!(instructions.get_extended(&b'z').is_none());
+        assert!(instructions.get(&b'p').is_some());
+        assert!(instructions.get_literal(&b'1').is_some());
+        assert_eq!(
+            instructions.opcodes(),
+            InstructionSet::<&[u8]>::standard().opcodes()
+        );
+    }
+
+    #[test]
+    fn arithmetic_instructions_are_opt_in() {
+        let instructions = InstructionSet::<&[u8]>::new_with(|s| {
+            s.with_arithmetic_instructions();
+        });
+        for opcode in b"+-*/%" {
+            assert!(instructions.get(opcode).is_some());
+        }
+        // Only the arithmetic group was registered
+        assert!(instructions.get(&b'p').is_none());
+        assert!(InstructionSet::<&[u8]>::new().get(&b'+').is_none());
+    }
+
+    /// Run `program` with every check enabled, returning the output and the main stack depth
+    fn run_checked<'a>(
+        instructions: InstructionSet<&'a [u8]>,
+        program: &'a [u8],
+    ) -> (String, usize) {
+        let mut vm = Vm::new(instructions, program);
+        vm.enable_effect_checks();
+        vm.enable_bracket_checks();
+        let mut output = Vec::new();
+        vm.run_with_input_output(&[], &mut output).unwrap();
+        (
+            String::from_utf8(output).unwrap(),
+            vm.get_context().stack_len(),
+        )
+    }
+
+    #[test]
+    fn programs_behave_the_same_under_another_syntax() {
+        let syntax = Syntax {
+            comment: b'&',
+            quote: b'^',
+            string: b'|',
+            loop_open: b'<',
+            loop_close: b'>',
+            skip_open: 1,
+            skip_close: 2,
+            extended: b'?',
+        };
+        let translate = |program: &[u8]| {
+            program
+                .iter()
+                .map(|op| match op {
+                    b'#' => syntax.comment,
+                    b'\'' => syntax.quote,
+                    b'"' => syntax.string,
+                    b'[' => syntax.loop_open,
+                    b']' => syntax.loop_close,
+                    b'(' => syntax.skip_open,
+                    b')' => syntax.skip_close,
+                    op => *op,
+                })
+                .collect::<Vec<_>>()
+        };
+        let with_syntax = InstructionSet::new_with(|me| {
+            me.with_base_instructions(syntax)
+                .with_arithmetic_instructions()
+                .with_list_instructions()
+                .with_string_instructions()
+                .with_conversion_instructions();
+        });
+
+        // Comments, a stack effect, a loop, Char and Str literals, skipping a literal with 's'
+        // and a skipped block
+        let program: &[u8] =
+            b"#( -- n )#0 [o1+dd3=apms]\" done\"p'\\np ts\"no\" \"yes\"p(\"x\"p)#end#";
+        let expected = run_checked(InstructionSet::standard(), program);
+        assert_eq!(expected, ("123 done\nyes".into(), 1));
+        assert_eq!(run_checked(with_syntax, &translate(program)), expected);
+    }
+
+    /// Instructions which can be told apart by the Int they push
+    const PUSHES: [Instruction<&[u8]>; 3] = [
+        |ctx| {
+            ctx.push(Data::Int(0));
+            Ok(())
+        },
+        |ctx| {
+            ctx.push(Data::Int(1));
+            Ok(())
+        },
+        |ctx| {
+            ctx.push(Data::Int(2));
+            Ok(())
+        },
+    ];
+
+    fn pushed(instruction: Instruction<&[u8]>) -> Data {
+        let mut ctx = Context::new(&[][..]);
+        instruction(&mut ctx).unwrap();
+        ctx.pop().unwrap()
+    }
+
+    #[test]
+    fn dispatch_matches_a_map() {
+        let mut instructions = InstructionSet::new();
+        let mut map = HashMap::new();
+        let mut seed = 0x2545_f491_u32;
+        for _ in 0..2000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let opcode = (seed >> 16) as u8;
+            match (seed >> 8) % 4 {
+                0 => {
+                    assert_eq!(
+                        instructions.remove(&opcode).map(pushed),
+                        map.remove(&opcode).map(pushed)
+                    );
+                }
+                i => {
+                    let instruction = PUSHES[i as usize - 1];
+                    instructions.insert(opcode, instruction);
+                    map.insert(opcode, instruction);
+                }
+            }
+        }
+
+        for opcode in 0..=u8::MAX {
+            assert_eq!(
+                instructions.get(&opcode).map(pushed),
+                map.get(&opcode).copied().map(pushed),
+                "opcode {}",
+                opcode
+            );
+        }
+        let mut opcodes = map.keys().copied().collect::<Vec<_>>();
+        opcodes.sort_unstable();
+        assert_eq!(instructions.opcodes(), opcodes);
+    }
+
+    #[test]
+    fn dispatch_covers_every_opcode() {
+        let instructions = InstructionSet::new_with(|me| {
+            for opcode in 0..=u8::MAX {
+                me.insert(opcode, PUSHES[opcode as usize % 3]);
+            }
+        });
+        assert_eq!(instructions.opcodes().len(), 256);
+        assert_eq!(instructions.get(&0).map(pushed), Some(Data::Int(0)));
+        assert_eq!(instructions.get(&255).map(pushed), Some(Data::Int(0)));
+        assert_eq!(instructions.get(&128).map(pushed), Some(Data::Int(2)));
+
+        let mut vm = Vm::new(instructions, &[0x00, 0xff, 0x80, 0x01][..]);
+        vm.run().unwrap();
+        let stack = vm.get_context_mut().take_stack();
+        assert_eq!(stack, [0, 0, 2, 1].map(Data::Int).to_vec());
+    }
 }
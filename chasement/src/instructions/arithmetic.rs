@@ -1,11 +1,380 @@
+//! Arithmetic on Ints wraps around on overflow in every build profile, `?>1+` pushes
+//! `i64::MIN` and `?<1n/` (`i64::MIN / -1`) pushes `i64::MIN` as well.
+//!
+//! The binary operators also work on Floats. If one operand is a Float and the other one an
+//! Int, the Int is converted to a Float first and the result is a Float. Float arithmetic
+//! follows IEEE 754, dividing a Float by zero results in an infinity or NaN instead of an error.
+
+use super::{division_by_zero, invalid_operands, InstructionSet};
+use crate::{Context, Data, ProgramStorage, VmResult};
+
+pub fn add_arithmetic_instructions<P: ProgramStorage>(instructions: &mut InstructionSet<P>) {
+    instructions.insert(b'+', plus);
+    instructions.insert(b'-', minus);
+    instructions.insert(b'*', mul);
+    instructions.insert(b'/', div);
+    instructions.insert(b'%', modulo);
+    instructions.insert(b'n', negate);
+    instructions.insert(b'u', abs);
+    instructions.insert(b'g', sign);
+
+    instructions.insert_extended(b'm', min);
+    instructions.insert_extended(b'M', max);
+}
+
 /// ('+') Pops two values of the stack and pushes their sum.
-/// Works only for Floats, Ints and Strings
-pub fn plus(ctx: &mut Context) {
+/// Works for Ints and Floats or any combination of Strs and Chars which are concatenated
+/// to a Str. The value below the top comes first, `"foo" "bar"+` pushes "foobar".
+pub fn plus<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
     match (ctx.pop(), ctx.pop()) {
-        (Some(Data::Int(a)), Some(Data::Int(b))) => ctx.push(Data::Int(a + b)),
-        (a, b) => error(&format!(
-            "'+' (Plus) called on invalid combination ({:?}, {:?})",
-            a, b
-        )),
+        (Some(Data::Int(a)), Some(Data::Int(b))) => ctx.push(Data::Int(b.wrapping_add(a))),
+        (Some(Data::Str(a)), Some(Data::Str(mut b))) => {
+            b.push_str(&a);
+            ctx.push(Data::Str(b));
+        }
+        (Some(Data::Char(a)), Some(Data::Str(mut b))) => {
+            b.push(a);
+            ctx.push(Data::Str(b));
+        }
+        (Some(Data::Str(a)), Some(Data::Char(b))) => ctx.push(Data::Str(format!("{}{}", b, a))),
+        (Some(Data::Char(a)), Some(Data::Char(b))) => ctx.push(Data::Str([b, a].iter().collect())),
+        (a, b) => match as_floats(&a, &b) {
+            Some((a, b)) => ctx.push(Data::Float(b + a)),
+            None => invalid_operands(
+                ctx,
+                "Int or Float, Int or Float or Str/Char, Str/Char",
+                &[a, b],
+            )?,
+        },
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+/// ('-') Pops two values of the stack and pushes their difference.
+/// The top value is subtracted from the one below it, `5 3-` pushes 2.
+pub fn minus<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match (ctx.pop(), ctx.pop()) {
+        (Some(Data::Int(a)), Some(Data::Int(b))) => ctx.push(Data::Int(b.wrapping_sub(a))),
+        (a, b) => match as_floats(&a, &b) {
+            Some((a, b)) => ctx.push(Data::Float(b - a)),
+            None => invalid_operands(ctx, "Int or Float, Int or Float", &[a, b])?,
+        },
+    }
+    Ok(())
+}
+
+/// ('*') Pops two values of the stack and pushes their product.
+pub fn mul<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match (ctx.pop(), ctx.pop()) {
+        (Some(Data::Int(a)), Some(Data::Int(b))) => ctx.push(Data::Int(b.wrapping_mul(a))),
+        (a, b) => match as_floats(&a, &b) {
+            Some((a, b)) => ctx.push(Data::Float(b * a)),
+            None => invalid_operands(ctx, "Int or Float, Int or Float", &[a, b])?,
+        },
+    }
+    Ok(())
+}
+
+/// ('/') Pops two values of the stack and pushes their quotient.
+/// The value below the top is divided by the top value, `6 3/` pushes 2.
+pub fn div<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match (ctx.pop(), ctx.pop()) {
+        (Some(Data::Int(0)), Some(Data::Int(_))) => division_by_zero(ctx)?,
+        (Some(Data::Int(a)), Some(Data::Int(b))) => ctx.push(Data::Int(b.wrapping_div(a))),
+        (a, b) => match as_floats(&a, &b) {
+            Some((a, b)) => ctx.push(Data::Float(b / a)),
+            None => invalid_operands(ctx, "Int or Float, Int or Float", &[a, b])?,
+        },
+    }
+    Ok(())
+}
+
+/// ('%') Pops two values of the stack and pushes the remainder of their division.
+/// The value below the top is divided by the top value, `7 3%` pushes 1.
+/// The quotient is truncated towards zero for Ints and Floats, so the remainder has the sign of
+/// the divided value: `7.5 2%` pushes 1.5 and `7.5n 2%` pushes -1.5.
+pub fn modulo<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match (ctx.pop(), ctx.pop()) {
+        (Some(Data::Int(0)), Some(Data::Int(_))) => division_by_zero(ctx)?,
+        (Some(Data::Int(a)), Some(Data::Int(b))) => ctx.push(Data::Int(b.wrapping_rem(a))),
+        (a, b) => match as_floats(&a, &b) {
+            Some((a, b)) => ctx.push(Data::Float(b % a)),
+            None => invalid_operands(ctx, "Int or Float, Int or Float", &[a, b])?,
+        },
+    }
+    Ok(())
+}
+
+/// ('n') Pops a value of the stack and pushes its negation.
+/// Works only for Ints and Floats, negating `i64::MIN` wraps around to itself.
+///
+/// As digits can't be prefixed with a minus sign this is the way to write negative literals:
+/// `5n` pushes -5.
+pub fn negate<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Int(i)) => ctx.push(Data::Int(i.wrapping_neg())),
+        Some(Data::Float(f)) => ctx.push(Data::Float(-f)),
+        v => invalid_operands(ctx, "Int or Float", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('u') Pops a value of the stack and pushes its absolute value.
+/// Works only for Ints and Floats, like `negate` the absolute value of `i64::MIN` wraps around
+/// to `i64::MIN` as it is not representable.
+pub fn abs<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Int(i)) => ctx.push(Data::Int(i.wrapping_abs())),
+        Some(Data::Float(f)) => ctx.push(Data::Float(f.abs())),
+        v => invalid_operands(ctx, "Int or Float", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('g') Pops a value of the stack and pushes its sign.
+/// For Ints this is -1, 0 or 1, for Floats -1.0, 0.0 or 1.0. NaN stays NaN.
+pub fn sign<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Int(i)) => ctx.push(Data::Int(i.signum())),
+        Some(Data::Float(f)) if f == 0.0 || f.is_nan() => ctx.push(Data::Float(f)),
+        Some(Data::Float(f)) => ctx.push(Data::Float(f.signum())),
+        v => invalid_operands(ctx, "Int or Float", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('?m') Pops two values of the stack and pushes the smaller one.
+/// Mixed Ints and Floats are compared as Floats and the result is a Float. If one Float is NaN
+/// the other value is pushed, like `f64::min`.
+pub fn min<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match (ctx.pop(), ctx.pop()) {
+        (Some(Data::Int(a)), Some(Data::Int(b))) => ctx.push(Data::Int(b.min(a))),
+        (a, b) => match as_floats(&a, &b) {
+            Some((a, b)) => ctx.push(Data::Float(b.min(a))),
+            None => invalid_operands(ctx, "Int or Float, Int or Float", &[a, b])?,
+        },
+    }
+    Ok(())
+}
+
+/// ('?M') Pops two values of the stack and pushes the larger one, see [`min`]
+pub fn max<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match (ctx.pop(), ctx.pop()) {
+        (Some(Data::Int(a)), Some(Data::Int(b))) => ctx.push(Data::Int(b.max(a))),
+        (a, b) => match as_floats(&a, &b) {
+            Some((a, b)) => ctx.push(Data::Float(b.max(a))),
+            None => invalid_operands(ctx, "Int or Float, Int or Float", &[a, b])?,
+        },
+    }
+    Ok(())
+}
+
+/// Get both operands as Floats if one of them is a Float and the other one a Float or an Int
+fn as_floats(a: &Option<Data>, b: &Option<Data>) -> Option<(f64, f64)> {
+    let to_float = |val: &Option<Data>| match val {
+        Some(Data::Float(f)) => Some(*f),
+        Some(Data::Int(i)) => Some(*i as f64),
+        _ => None,
+    };
+
+    match (a, b) {
+        (Some(Data::Int(_)), Some(Data::Int(_))) => None,
+        _ => Some((to_float(a)?, to_float(b)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_util::{output, run, stack},
+        Data, InstructionSet, Vm, VmError,
+    };
+
+    /// Push `values` onto the stack of an embedded Vm, run `program` and get the Float on top
+    fn with_pushed(values: &[Data], program: &str) -> f64 {
+        let mut vm = Vm::new(InstructionSet::standard(), program);
+        for value in values {
+            vm.get_context_mut().push(value.clone());
+        }
+        vm.run().unwrap();
+        match vm.get_context_mut().take_stack().as_slice() {
+            [Data::Float(f)] => *f,
+            v => panic!("Expected one Float, found {:?}", v),
+        }
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} is not close to {}", a, b);
+    }
+
+    fn float(program: &str) -> f64 {
+        match stack(program).as_slice() {
+            [Data::Float(f)] => *f,
+            v => panic!("Expected one Float, found {:?}", v),
+        }
+    }
+
+    #[test]
+    fn negate() {
+        assert_eq!(output("5n p"), Ok("-5".into()));
+        assert_eq!(stack("5nn"), vec![Data::Int(5)]);
+        assert_eq!(stack("0n"), vec![Data::Int(0)]);
+        assert_eq!(float("2Fn"), -2.0);
+        assert!(matches!(run("tn"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("'an"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("\"a\"n"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("n"), Err(VmError::StackUnderflow { .. })));
+    }
+
+    #[test]
+    fn abs() {
+        assert_eq!(stack("5nu"), vec![Data::Int(5)]);
+        assert_eq!(stack("5u"), vec![Data::Int(5)]);
+        assert_eq!(stack("0u"), vec![Data::Int(0)]);
+        // i64::MIN has no positive counterpart and wraps around instead of panicking
+        assert_eq!(stack("?<u"), vec![Data::Int(i64::MIN)]);
+        assert_eq!(stack("?<1+u"), vec![Data::Int(i64::MAX)]);
+        assert_eq!(float("5Fnu"), 5.0);
+        assert!(float("??u").is_nan());
+        assert!(matches!(run("'au"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn min_max() {
+        assert_eq!(stack("3 5n?m"), vec![Data::Int(-5)]);
+        assert_eq!(stack("3 5n?M"), vec![Data::Int(3)]);
+        assert_eq!(stack("4 4?m"), vec![Data::Int(4)]);
+        assert_eq!(stack("4 4?M"), vec![Data::Int(4)]);
+        // Mixed operands are compared as Floats
+        assert_eq!(float("2F 1?m"), 1.0);
+        assert_eq!(float("2F 1?M"), 2.0);
+        // NaN is ignored like by f64::min and f64::max
+        assert_eq!(float("??1F?m"), 1.0);
+        assert_eq!(float("1F???M"), 1.0);
+        assert!(float("?????m").is_nan());
+        assert!(matches!(run("1 'a?m"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("1?M"), Err(VmError::StackUnderflow { .. })));
+    }
+
+    #[test]
+    fn sign() {
+        assert_eq!(stack("5ng"), vec![Data::Int(-1)]);
+        assert_eq!(stack("0g"), vec![Data::Int(0)]);
+        assert_eq!(stack("7g"), vec![Data::Int(1)]);
+        assert_eq!(stack("?<g"), vec![Data::Int(-1)]);
+        assert_eq!(stack("?>g"), vec![Data::Int(1)]);
+        assert_eq!(float("5Fng"), -1.0);
+        assert_eq!(float("?ig"), 1.0);
+        // The sign of zero is kept
+        assert!(float("0Fng").is_sign_negative());
+        assert!(float("??g").is_nan());
+        assert!(matches!(run("tg"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    /// Int overflow wraps in debug and release builds alike, run with `--release` to check
+    /// the other profile
+    #[test]
+    fn overflow_wraps() {
+        let int = |program| match stack(program)[..] {
+            [Data::Int(i)] => i,
+            ref v => panic!("Expected one Int, found {:?}", v),
+        };
+        assert_eq!(int("?>1+"), i64::MIN);
+        assert_eq!(int("?<1-"), i64::MAX);
+        assert_eq!(int("?>2*"), -2);
+        assert_eq!(int("?<1n/"), i64::MIN);
+        assert_eq!(int("?<1n%"), 0);
+        assert_eq!(int("?<n"), i64::MIN);
+        assert_eq!(int("?>?>+"), -2);
+        // Strings and Floats don't overflow
+        assert_eq!(float("?>F1+"), i64::MAX as f64 + 1.0);
+        assert_eq!(stack("\"a\" 'b+"), vec![Data::Str("ab".into())]);
+    }
+
+    #[test]
+    fn plus_concatenates() {
+        let str = |s: &str| vec![Data::Str(s.into())];
+        // The value below the top comes first
+        assert_eq!(stack("\"foo\" \"bar\"+"), str("foobar"));
+        assert_eq!(stack("\"foo\" 'd+"), str("food"));
+        assert_eq!(stack("'a \"bc\"+"), str("abc"));
+        assert_eq!(stack("'a 'b+"), str("ab"));
+        assert_eq!(stack("1 2+"), vec![Data::Int(3)]);
+        assert_eq!(float("1 2F+"), 3.0);
+        assert_eq!(
+            run("t 1+"),
+            Err(VmError::TypeMismatch {
+                expected: "Int or Float, Int or Float or Str/Char, Str/Char",
+                found: "Int, Bool".into(),
+                pc: 3
+            })
+        );
+        assert!(matches!(run("\"a\" 1+"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn float_operands() {
+        let (a, b) = (Data::Float(7.5), Data::Float(2.0));
+        assert_close(with_pushed(&[a.clone(), b.clone()], "+"), 9.5);
+        assert_close(with_pushed(&[a.clone(), b.clone()], "-"), 5.5);
+        assert_close(with_pushed(&[a.clone(), b.clone()], "*"), 15.0);
+        assert_close(with_pushed(&[a.clone(), b.clone()], "/"), 3.75);
+        assert_close(with_pushed(&[a.clone(), b], "%"), 1.5);
+        assert_close(with_pushed(&[Data::Float(0.1), Data::Float(0.2)], "+"), 0.3);
+        // Programs can keep computing with the pushed Floats
+        assert_close(with_pushed(&[a], "2*1-"), 14.0);
+    }
+
+    #[test]
+    fn mixed_operands() {
+        // The Int is promoted no matter on which side it is
+        let (f, i) = (Data::Float(0.5), Data::Int(3));
+        assert_close(with_pushed(&[f.clone(), i.clone()], "+"), 3.5);
+        assert_close(with_pushed(&[i.clone(), f.clone()], "+"), 3.5);
+        assert_close(with_pushed(&[f.clone(), i.clone()], "-"), -2.5);
+        assert_close(with_pushed(&[i.clone(), f.clone()], "-"), 2.5);
+        assert_close(with_pushed(&[i.clone(), f.clone()], "*"), 1.5);
+        assert_close(with_pushed(&[i.clone(), f.clone()], "/"), 6.0);
+        assert_close(with_pushed(&[i, f.clone()], "%"), 0.0);
+        assert_close(with_pushed(&[f], "2/"), 0.25);
+    }
+
+    #[test]
+    fn float_remainder_truncates() {
+        // The remainder has the sign of the divided value
+        assert_close(
+            with_pushed(&[Data::Float(-7.5), Data::Float(2.0)], "%"),
+            -1.5,
+        );
+        assert_close(
+            with_pushed(&[Data::Float(7.5), Data::Float(-2.0)], "%"),
+            1.5,
+        );
+        assert_close(with_pushed(&[Data::Float(-7.5), Data::Int(2)], "%"), -1.5);
+        assert!(with_pushed(&[Data::Float(1.0), Data::Float(0.0)], "%").is_nan());
+    }
+
+    #[test]
+    fn float_division_by_zero() {
+        // Floats follow IEEE 754 instead of failing like Ints
+        assert_eq!(
+            with_pushed(&[Data::Float(1.0), Data::Int(0)], "/"),
+            f64::INFINITY
+        );
+        assert_eq!(
+            with_pushed(&[Data::Float(-1.0), Data::Float(0.0)], "/"),
+            f64::NEG_INFINITY
+        );
+        assert!(with_pushed(&[Data::Float(0.0), Data::Float(0.0)], "/").is_nan());
+        assert!(matches!(run("1 0/"), Err(VmError::DivisionByZero { .. })));
+        assert!(matches!(run("1 0%"), Err(VmError::DivisionByZero { .. })));
+    }
+
+    #[test]
+    fn float_type_errors() {
+        let mut vm = Vm::new(InstructionSet::standard(), "+");
+        vm.get_context_mut().push(Data::Float(1.0));
+        vm.get_context_mut().push(Data::Bool(true));
+        assert!(matches!(vm.run(), Err(VmError::TypeMismatch { .. })));
+    }
+}
@@ -1,260 +1,1080 @@
-use std::io::{self, Read};
+use super::{error, invalid_operands, stack_underflow, InstructionSet, Syntax};
+use crate::{
+    journal::JournalKind,
+    literal::{LiteralError, LiteralScanner},
+    Context, Data, Opcode, ProgramStorage, VmResult, MAX_CALL_DEPTH,
+};
 
-use super::{error, InstructionSet};
-use crate::{Context, Data, ProgramStorage};
-
-pub fn add_base_instructions<P: ProgramStorage>(instructions: &mut InstructionSet<P>) {
+pub fn add_base_instructions<P: ProgramStorage>(
+    instructions: &mut InstructionSet<P>,
+    syntax: &Syntax,
+) {
     instructions.insert(b'!', not);
-    instructions.insert(b'#', comment);
+    instructions.insert(syntax.comment, comment);
     instructions.insert(b',', input);
-    instructions.insert(b'\'', charify);
+    instructions.insert_literal(syntax.quote, scan_char);
+    instructions.insert_literal(syntax.string, scan_str);
     instructions.insert(b' ', nop);
     instructions.insert(b'\n', nop);
     instructions.insert(b'a', auxiliary_push);
     instructions.insert(b'd', dup);
     instructions.insert(b'e', empty);
-    instructions.insert(b'f', |ctx| ctx.push(Data::Bool(false)));
+    instructions.insert(b'f', |ctx| {
+        ctx.push(Data::Bool(false));
+        Ok(())
+    });
     instructions.insert(b'h', print_stack);
     instructions.insert(b'j', jump);
+    instructions.insert(b'k', pick);
+    instructions.insert(b'l', roll);
     instructions.insert(b'm', main_push);
     instructions.insert(b'o', drop);
     instructions.insert(b'p', print);
+    instructions.insert(b'r', rot);
     instructions.insert(b's', skip_if);
-    instructions.insert(b't', |ctx| ctx.push(Data::Bool(true)));
+    instructions.insert(b't', |ctx| {
+        ctx.push(Data::Bool(true));
+        Ok(())
+    });
+    instructions.insert(b'v', over);
     instructions.insert(b'w', swap);
     instructions.insert(b'x', exit);
     instructions.insert(b'z', aux_empty);
     for c in b'0'..=b'9' {
-        instructions.insert(c, digit);
+        instructions.insert_literal(c, scan_int);
     }
+    instructions.insert(b'D', depth);
+    instructions.insert(b'A', aux_depth);
+    instructions.insert(b'C', clear);
+    instructions.insert(b'V', aux_clear);
     instructions.insert(b'=', eq);
+    instructions.insert(b'N', not_eq);
+
+    instructions.insert_extended(b'<', int_min);
+    instructions.insert_extended(b'>', int_max);
+    instructions.insert_extended(b'i', float_infinity);
+    instructions.insert_extended(b'?', float_nan);
+    instructions.insert_extended(b'I', bool_to_int);
+    instructions.insert_extended(b'B', int_to_bool);
+    instructions.insert_extended(b'S', char_to_str);
+
+    instructions.insert(b'c', call_captured);
 
-    instructions.insert(b'[', cur_pc);
-    instructions.insert(b']', jump_back);
+    instructions.insert(syntax.loop_open, cur_pc);
+    instructions.insert(syntax.loop_close, jump_back);
 
-    instructions.insert(b'(', paren_open);
-    instructions.insert(b')', nop);
+    instructions.insert(syntax.skip_open, paren_open);
+    instructions.insert(syntax.skip_close, nop);
 }
 
 /// (' ') Do nothing. Represented by one spacebar
-pub fn nop<P: ProgramStorage>(_ctx: &mut Context<P>) {}
-
-/// ('#') Comment out everything to the next '#' or '\n'
-pub fn comment<P: ProgramStorage>(ctx: &mut Context<P>) {
-    ctx.advance();
-    while let Some(ch) = ctx.cur_byte() {
-        if ch == b'#' || ch == b'\n' {
-            break;
-        }
-        ctx.advance();
-    }
+pub fn nop<P: ProgramStorage>(_ctx: &mut Context<P>) -> VmResult {
+    Ok(())
 }
 
-/// ('0'-'9') Parse a number. Should only be entered through a digit.
-///
-/// If the current byte at the program counter is not a digit this will push 0.
-pub fn digit<P: ProgramStorage>(ctx: &mut Context<P>) {
-    let mut num = 0i64;
-    loop {
-        if let Some(digit) = ctx.cur_byte() {
-            if digit >= 10 + b'0' || digit < b'0' {
-                ctx.prev();
+/// ('#') Comment out everything to the next '#' or '\n'.
+/// A comment starting with `#{` is a block comment instead, which ends at the matching `#}` and
+/// can span multiple lines. Block comments nest, so `#{ a #{ b #} c #}` is one comment, which
+/// allows commenting out code that already contains comments.
+pub fn comment<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let end = comment_end(&ctx.program, ctx.get_pc(), ctx.syntax().comment);
+    ctx.set_pc(end);
+    Ok(())
+}
+
+/// Get the position of the byte ending the comment which starts at `pc`, see [`comment`].
+/// If the comment is never ended this is the end of the program.
+pub(crate) fn comment_end<P: ProgramStorage>(program: &P, pc: usize, marker: Opcode) -> usize {
+    let mut idx = pc + 1;
+    if program.opcode_at(idx) != Some(b'{') {
+        while let Some(op) = program.opcode_at(idx) {
+            if op == marker || op == b'\n' {
                 break;
             }
-            let digit = digit - b'0';
-            num *= 10;
+            idx += 1;
+        }
+        return idx;
+    }
 
-            num += digit as i64;
+    let mut depth = 1;
+    idx += 1;
+    while let Some(op) = program.opcode_at(idx) {
+        if op == marker && program.opcode_at(idx + 1) == Some(b'{') {
+            depth += 1;
+            idx += 1;
+        } else if op == marker && program.opcode_at(idx + 1) == Some(b'}') {
+            depth -= 1;
+            idx += 1;
+            if depth == 0 {
+                break;
+            }
         }
-        ctx.advance();
+        idx += 1;
+    }
+    idx
+}
+
+/// ('0'-'9') Literal for an Int, consumes all following digits.
+/// If the digits are followed by a '.' and another digit the literal is a Float like `3.14`,
+/// a '.' without a digit after it is not part of the literal, `3.` is the Int 3 and a '.'.
+/// An Int literal above the maximum Int is an error instead of wrapping around.
+pub fn scan_int(scanner: &LiteralScanner) -> Result<(Data, usize), LiteralError> {
+    // `None` once the literal overflowed, the digits are still consumed as it may be a Float
+    let mut num = Some((scanner.prefix() - b'0') as i64);
+    let mut len = 0;
+    while let Some(digit) = scanner.get(len).filter(u8::is_ascii_digit) {
+        num = num
+            .and_then(|num| num.checked_mul(10))
+            .and_then(|num| num.checked_add((digit - b'0') as i64));
+        len += 1;
+    }
+
+    let is_digit = |idx| scanner.get(idx).filter(u8::is_ascii_digit).is_some();
+    let text = |len| {
+        std::iter::once(scanner.prefix())
+            .chain((0..len).filter_map(|idx| scanner.get(idx)))
+            .map(char::from)
+            .collect::<String>()
+    };
+    if scanner.get(len) != Some(b'.') || !is_digit(len + 1) {
+        return match num {
+            Some(num) => Ok((Data::Int(num), len)),
+            None => Err(LiteralError::Invalid(format!(
+                "Int literal {} is out of range",
+                text(len)
+            ))),
+        };
+    }
+
+    len += 1;
+    while is_digit(len) {
+        len += 1;
     }
 
-    ctx.push(Data::Int(num as i64))
+    let text = text(len);
+    match text.parse() {
+        Ok(float) => Ok((Data::Float(float), len)),
+        Err(_) => Err(LiteralError::Invalid(format!(
+            "Invalid Float literal {}",
+            text
+        ))),
+    }
 }
 
 /// ('a') Pop a value from the main stack and push it to the auxiliary stack.
 /// Does nothing if stack is empty
-pub fn auxiliary_push<P: ProgramStorage>(ctx: &mut Context<P>) {
-    ctx.to_auxiliary()
+pub fn auxiliary_push<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.to_auxiliary();
+    Ok(())
 }
 
 /// ('m') Pop a value from the main stack and push it to the auxiliary stack
 /// Does nothing if auxiliary stack is empty
-pub fn main_push<P: ProgramStorage>(ctx: &mut Context<P>) {
-    ctx.to_main()
+pub fn main_push<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.to_main();
+    Ok(())
 }
 
-/// ('p') Print the top element of the stack
-pub fn print<P: ProgramStorage>(ctx: &mut Context<P>) {
+/// ('p') Print the top element of the stack, control characters are handled according to
+/// `Context::sanitize`
+pub fn print<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
     if let Some(val) = ctx.pop() {
-        print!("{}", val);
+        ctx.write_output(&ctx.sanitize().apply(&val.to_string()))?;
+        ctx.journal_record(JournalKind::Print, vec![val]);
+        Ok(())
     } else {
-        error("Called print on an empty stack")
+        stack_underflow(ctx)
     }
 }
 
 /// ('d') Duplicate the top element of the stack
-pub fn dup<P: ProgramStorage>(ctx: &mut Context<P>) {
+pub fn dup<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
     let val = if let Some(val) = ctx.top() {
         val.clone()
     } else {
-        error("Called dup on an empty stack")
+        return stack_underflow(ctx);
     };
 
-    ctx.push(val.clone())
+    ctx.push(val);
+    Ok(())
 }
 
 /// ('e') Push to the stack wether the stack is empty.
 /// This pushes true if the stack is empty fals otherwise.
-pub fn empty<P: ProgramStorage>(ctx: &mut Context<P>) {
-    ctx.push(Data::Bool(matches!(ctx.top(), None)))
+pub fn empty<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.push(Data::Bool(ctx.top().is_none()));
+    Ok(())
 }
 
 /// ('j') Jump to the address provided by the top element. Pops one value of the stack.
 /// Exits with an error if top element is not an int, or stack is empty.
-pub fn jump<P: ProgramStorage>(ctx: &mut Context<P>) {
+/// Also exits with an error if the address is negative or past the end of the program.
+pub fn jump<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
     match ctx.pop() {
+        Some(Data::Int(i)) if i < 0 => {
+            error(ctx, &format!("'j' (Jump) to negative address {}", i))?
+        }
+        Some(Data::Int(i)) if ctx.program.opcode_at(i as usize).is_none() => error(
+            ctx,
+            &format!("'j' (Jump) to {} past the end of the program", i),
+        )?,
         Some(Data::Int(i)) => ctx.set_pc((i as usize).wrapping_sub(1)),
-        None => error("Called jump on empty stack"),
-        _ => error("Called jump on non int element"),
+        v => invalid_operands(ctx, "Int", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('c') Pops a length and a start address and calls that range of the program as a
+/// subroutine. Once the end of the range is reached execution continues after the 'c'.
+///
+/// Together with '[' this allows reusable blocks, `[(1p)` pushes the address of '[',
+/// the block body "1p" then starts 2 bytes later and is 2 bytes long.
+/// Exits with an error if the values are no positive ints or the call depth exceeds
+/// `MAX_CALL_DEPTH`.
+pub fn call_captured<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let (start, len) = match (ctx.pop(), ctx.pop()) {
+        (Some(Data::Int(len)), Some(Data::Int(start))) if len >= 0 && start >= 0 => {
+            (start as usize, len as usize)
+        }
+        (Some(Data::Int(len)), Some(Data::Int(start))) => error(
+            ctx,
+            &format!("'c' (Call) called with negative range {} {}", start, len),
+        )?,
+        (a, b) => invalid_operands(ctx, "Int, Int", &[a, b])?,
+    };
+
+    if ctx.call_depth() >= MAX_CALL_DEPTH {
+        return error(
+            ctx,
+            &format!("Exceeded maximum call depth of {}", MAX_CALL_DEPTH),
+        );
     }
+
+    ctx.call(start, len);
+    Ok(())
 }
 
 /// ('s') Pops the top value and skips one instruction if the top value is a true bool.
-pub fn skip_if<P: ProgramStorage>(ctx: &mut Context<P>) {
+/// A literal like `12` or `'a` is skipped as a whole.
+pub fn skip_if<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
     match ctx.pop() {
-        Some(Data::Bool(true)) => ctx.advance(),
+        Some(Data::Bool(true)) => {
+            let len = ctx.token_len(ctx.get_pc() + 1);
+            ctx.set_pc(ctx.get_pc() + len)
+        }
         Some(Data::Bool(false)) => (),
-        _ => error("Skip called on a non boolean value"),
+        v => invalid_operands(ctx, "Bool", &[v])?,
     }
+    Ok(())
 }
 
 /// ('!') Pops a value of the stack and pushes the bitwise negation
-pub fn not<P: ProgramStorage>(ctx: &mut Context<P>) {
+pub fn not<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
     match ctx.pop() {
         Some(Data::Bool(b)) => ctx.push(Data::Bool(!b)),
         Some(Data::Int(i)) => ctx.push(Data::Int(!i)),
-        _ => error("Not called on a non Int or Bool value"),
+        v => invalid_operands(ctx, "Int or Bool", &[v])?,
     }
+    Ok(())
 }
 
 /// ('=') Pops two values and pushes wether they are equal (type and value)
-pub fn eq<P: ProgramStorage>(ctx: &mut Context<P>) {
+pub fn eq<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let equal = pop_equal(ctx)?;
+    ctx.push(Data::Bool(equal));
+    Ok(())
+}
+
+/// ('N') Pops two values and pushes wether they are not equal (type or value)
+pub fn not_eq<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let equal = pop_equal(ctx)?;
+    ctx.push(Data::Bool(!equal));
+    Ok(())
+}
+
+/// Pops two values and compares them, shared by `eq` and `not_eq`
+fn pop_equal<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult<bool> {
     match (ctx.pop(), ctx.pop()) {
-        (Some(a), Some(b)) => ctx.push(Data::Bool(a == b)),
-        (a, b) => error(&format!(
-            "'=' (Eq) called on invalid combination ({:?}, {:?})",
-            a, b
-        )),
+        (Some(a), Some(b)) => Ok(a == b),
+        _ => stack_underflow(ctx),
     }
 }
 
+/// ('?<') Push the smallest possible Int (`i64::MIN`)
+pub fn int_min<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.push(Data::Int(i64::MIN));
+    Ok(())
+}
+
+/// ('?>') Push the biggest possible Int (`i64::MAX`)
+pub fn int_max<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.push(Data::Int(i64::MAX));
+    Ok(())
+}
+
+/// ('?i') Push positive infinity as Float
+pub fn float_infinity<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.push(Data::Float(f64::INFINITY));
+    Ok(())
+}
+
+/// ('??') Push NaN as Float
+pub fn float_nan<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.push(Data::Float(f64::NAN));
+    Ok(())
+}
+
+/// ('?I') Pop a Bool and push 1 for true and 0 for false
+pub fn bool_to_int<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Bool(b)) => ctx.push(Data::Int(b as i64)),
+        v => invalid_operands(ctx, "Bool", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('?B') Pop an Int and push false for 0 and true for every other Int
+pub fn int_to_bool<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Int(i)) => ctx.push(Data::Bool(i != 0)),
+        v => invalid_operands(ctx, "Int", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('?S') Pop a Char and push a Str containing only that Char
+pub fn char_to_str<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Char(c)) => ctx.push(Data::Str(c.to_string())),
+        v => invalid_operands(ctx, "Char", &[v])?,
+    }
+    Ok(())
+}
+
 /// ('h') Print the complete stack
-pub fn print_stack<P: ProgramStorage>(ctx: &mut Context<P>) {
-    println!("Main: [");
+pub fn print_stack<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let mut out = String::from("Main: [\n");
     for val in ctx.stack_iter() {
-        println!("    {:?},", val);
+        out += &format!("    {:?},\n", val);
     }
-    println!("]");
-    println!("Aux: [");
+    out += "]\nAux: [\n";
     for val in ctx.aux_stack_iter() {
-        println!("    {:?},", val);
-    }
-    println!("]");
-}
-
-/// (',') Read one ascii char from stdin
-pub fn input<P: ProgramStorage>(ctx: &mut Context<P>) {
-    // TODO this could be made more efficient
-    let mut buf = [0; 1];
-    io::stdin().read(&mut buf).unwrap();
-    ctx.push(Data::Char(buf[0] as char));
-}
-
-/// ('\'') Push next byte as char to the stack
-pub fn charify<P: ProgramStorage>(ctx: &mut Context<P>) {
-    ctx.advance();
-    if let Some(byte) = ctx.cur_byte() {
-        // Special case for escape sequence
-        if byte == b'\\' {
-            ctx.advance();
-            let byte2 = ctx.cur_byte().unwrap();
-            // Match over all supported escape sequences
-            match byte2 {
-                b'n' => ctx.push(Data::Char('\n')),
-                b => error(&format!("Invalid escape sequence \\{}", b as char)),
+        out += &format!("    {:?},\n", val);
+    }
+    out += "]\n";
+    ctx.write_output(&out)?;
+    ctx.journal_record(JournalKind::PrintStack, Vec::new());
+    Ok(())
+}
+
+/// (',') Read one ascii char from the input, stdin by default. Pushes '\0' at the end of the
+/// input.
+pub fn input<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let byte = ctx.read_input_byte()?.unwrap_or(0);
+    let val = Data::Char(byte as char);
+    ctx.journal_record(JournalKind::Input, vec![val.clone()]);
+    ctx.push(val);
+    Ok(())
+}
+
+/// ('\'') Literal for a Char, the next char or an escape sequence. The char is decoded as
+/// UTF-8, `'é` pushes 'é' and consumes both of its bytes.
+/// Supports the escape sequences `\n`, `\t`, `\r`, `\0`, `\\` and `\'`.
+pub fn scan_char(scanner: &LiteralScanner) -> Result<(Data, usize), LiteralError> {
+    // Special case for escape sequence
+    if scanner.expect(0)? != b'\\' {
+        let (c, width) = scanner.expect_char(0)?;
+        return Ok((Data::Char(c), width));
+    }
+
+    match scanner.expect(1)? {
+        b if b == scanner.prefix() => Ok((Data::Char(b as char), 2)),
+        b => Ok((Data::Char(unescape(b)? as char), 2)),
+    }
+}
+
+/// Get the byte written as the escape sequence `\` followed by `byte`, the sequences shared by
+/// Char and Str literals
+fn unescape(byte: u8) -> Result<u8, LiteralError> {
+    match byte {
+        b'n' => Ok(b'\n'),
+        b't' => Ok(b'\t'),
+        b'r' => Ok(b'\r'),
+        b'0' => Ok(b'\0'),
+        b'\\' => Ok(b'\\'),
+        b => Err(LiteralError::Invalid(format!(
+            "Invalid escape sequence \\{}",
+            b as char
+        ))),
+    }
+}
+
+/// ('"') Literal for a Str, everything up to the next unescaped '"'.
+/// Supports the escape sequences `\"` and the ones of Char literals except `\'`.
+pub fn scan_str(scanner: &LiteralScanner) -> Result<(Data, usize), LiteralError> {
+    let delimiter = scanner.prefix();
+    let mut s = String::new();
+    let mut len = 0;
+    loop {
+        match scanner.expect(len)? {
+            b if b == delimiter => break,
+            b'\\' => {
+                len += 1;
+                match scanner.expect(len)? {
+                    b if b == delimiter => s.push(b as char),
+                    b => s.push(unescape(b)? as char),
+                }
+                len += 1;
+            }
+            _ => {
+                let (c, width) = scanner.expect_char(len)?;
+                s.push(c);
+                len += width;
             }
-        } else {
-            ctx.push(Data::Char(byte as char))
         }
-    } else {
-        error("Used ' directly before EOF")
     }
+
+    // The closing delimiter is consumed too
+    Ok((Data::Str(s), len + 1))
 }
 
 /// ('[') Push current pc to the stack as int
-pub fn cur_pc<P: ProgramStorage>(ctx: &mut Context<P>) {
+pub fn cur_pc<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
     ctx.push(Data::Int(ctx.get_pc() as i64));
+    Ok(())
 }
 
 /// (']') Jump back to the last open square bracket '['
-pub fn jump_back<P: ProgramStorage>(ctx: &mut Context<P>) {
+pub fn jump_back<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let Syntax {
+        loop_open,
+        loop_close,
+        ..
+    } = *ctx.syntax();
     let mut cnt = 0;
     while let Some(b) = ctx.cur_byte() {
-        match (b, cnt) {
-            (b'[', 1) => {
+        if b == loop_open {
+            if cnt == 1 {
                 ctx.prev();
                 break;
             }
-            (b'[', _) => {
-                cnt -= 1;
-            }
-            (b']', _) => {
-                cnt += 1;
-            }
-            _ => (),
+            cnt -= 1;
+        } else if b == loop_close {
+            cnt += 1;
         }
         ctx.prev();
     }
+    Ok(())
 }
 
 /// ('(') Jump ahead to the next closed paranthese ')'
-pub fn paren_open<P: ProgramStorage>(ctx: &mut Context<P>) {
+pub fn paren_open<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let Syntax {
+        skip_open,
+        skip_close,
+        ..
+    } = *ctx.syntax();
     let mut cnt = 0;
     while let Some(byte) = ctx.cur_byte() {
-        match (byte, cnt) {
-            (b')', 1) => break,
-            (b')', _) => cnt -= 1,
-            (b'(', _) => cnt += 1,
-            _ => (),
+        if byte == skip_close {
+            if cnt == 1 {
+                break;
+            }
+            cnt -= 1;
+        } else if byte == skip_open {
+            cnt += 1;
         }
         ctx.advance();
     }
+    Ok(())
 }
 
-/// ('w') Swap the top two values, panics if there are less than two values on the stack
-pub fn swap<P: ProgramStorage>(ctx: &mut Context<P>) {
+/// ('w') Swap the top two values.
+/// Fails with `VmError::StackUnderflow` if there are less than two values on the stack.
+pub fn swap<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
     match (ctx.pop(), ctx.pop()) {
         (Some(a), Some(b)) => {
             ctx.push(a);
             ctx.push(b);
         }
-        v => error(&format!("'w' (Swap) called on invalid stack ({:?})", v)),
+        _ => stack_underflow(ctx)?,
     }
+    Ok(())
+}
+
+/// ('v') Copy the second value to the top, `a b` (b on top) becomes `a b a`
+pub fn over<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let val = match ctx.peek_at(1) {
+        Some(val) => val.clone(),
+        None => return stack_underflow(ctx),
+    };
+
+    ctx.push(val);
+    Ok(())
+}
+
+/// ('k') Pop an Int n and copy the value n positions below the top to the top.
+/// The top is at position 0 after popping n, so `0k` is the same as 'd' and `1k` as 'v'.
+pub fn pick<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let n = match ctx.pop() {
+        Some(Data::Int(n)) => n,
+        v => invalid_operands(ctx, "Int", &[v])?,
+    };
+
+    let val = match usize::try_from(n).ok().and_then(|n| ctx.peek_at(n)) {
+        Some(val) => val.clone(),
+        None => {
+            return error(
+                ctx,
+                &format!(
+                    "'k' (Pick) called with {} but the stack holds {} values",
+                    n,
+                    ctx.stack_len()
+                ),
+            )
+        }
+    };
+
+    ctx.push(val);
+    Ok(())
+}
+
+/// ('l') Pop an Int n and move the value n positions below the top to the top.
+/// The top is at position 0 after popping n, so `0l` does nothing, `1l` is the same as 'w'
+/// and `2l` as 'r'.
+pub fn roll<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let n = match ctx.pop() {
+        Some(Data::Int(n)) => n,
+        v => invalid_operands(ctx, "Int", &[v])?,
+    };
+
+    match usize::try_from(n).ok().and_then(|n| ctx.remove_at(n)) {
+        Some(val) => ctx.push(val),
+        None => error(ctx, &format!("'l' (Roll) called with {} out of range", n))?,
+    }
+    Ok(())
+}
+
+/// ('r') Rotate the top three values, the third value is moved to the top.
+/// `a b c` (c on top) becomes `b c a` (a on top).
+/// With less than three values the stack is left unchanged and the error lists its values.
+pub fn rot<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match (ctx.pop(), ctx.pop(), ctx.pop()) {
+        (Some(c), Some(b), Some(a)) => {
+            ctx.push(b);
+            ctx.push(c);
+            ctx.push(a);
+        }
+        (c, b, _) => {
+            let values = [b, c].into_iter().flatten().collect::<Vec<_>>();
+            let message = format!("'r' (Rot) needs three values but the stack is {:?}", values);
+            for val in values {
+                ctx.push(val);
+            }
+            error(ctx, &message)?
+        }
+    }
+    Ok(())
 }
 
 /// ('o') Drop the top value
-pub fn drop<P: ProgramStorage>(ctx: &mut Context<P>) {
+pub fn drop<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
     ctx.pop();
+    Ok(())
 }
 
-/// ('x') Drop the top value
-pub fn exit<P: ProgramStorage>(_ctx: &mut Context<P>) {
-    // TODO probably all the exits should rather be handled through a custom panic hook
-    std::process::exit(0);
+/// ('x') Exit the program. The Vm stops without an error after this instruction, the process
+/// of the host keeps running.
+pub fn exit<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.journal_record(JournalKind::Exit, Vec::new());
+    ctx.flush_output()?;
+    ctx.halt();
+    Ok(())
 }
 
 /// ('z') Auxiliary stack zero. Push if the auxiliary stack is empty
-pub fn aux_empty<P: ProgramStorage>(ctx: &mut Context<P>) {
-    ctx.push(Data::Bool(matches!(ctx.aux_top(), None)))
+pub fn aux_empty<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.push(Data::Bool(ctx.aux_top().is_none()));
+    Ok(())
+}
+
+/// ('D') Push the number of values on the main stack, not counting the pushed value
+pub fn depth<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.push(Data::Int(ctx.stack_len() as i64));
+    Ok(())
+}
+
+/// ('A') Push the number of values on the auxiliary stack
+pub fn aux_depth<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.push(Data::Int(ctx.aux_len() as i64));
+    Ok(())
+}
+
+/// ('C') Remove all values of the main stack, the auxiliary stack is kept
+pub fn clear<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.clear_stack();
+    Ok(())
+}
+
+/// ('V') Remove all values of the auxiliary stack, the main stack is kept
+pub fn aux_clear<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.clear_aux();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_util::{output, run, run_full, stack},
+        Context, Data, InstructionSet, Vm, VmError,
+    };
+
+    #[test]
+    fn exit_halts_without_ending_the_process() {
+        assert_eq!(output("1p x 2p").unwrap(), "1");
+        assert_eq!(stack("1 2x3"), vec![Data::Int(1), Data::Int(2)]);
+    }
+
+    #[test]
+    fn constants_are_extended_instructions() {
+        assert_eq!(
+            stack("?<?>?i"),
+            vec![
+                Data::Int(i64::MIN),
+                Data::Int(i64::MAX),
+                Data::Float(f64::INFINITY)
+            ]
+        );
+        assert!(matches!(stack("??")[..], [Data::Float(f)] if f.is_nan()));
+        // 's' skips the whole extended instruction
+        assert_eq!(stack("ts?<1"), vec![Data::Int(1)]);
+    }
+
+    #[test]
+    fn unknown_extended_instruction() {
+        assert_eq!(
+            run("1?%"),
+            Err(VmError::UnknownOpcode {
+                opcode: b'%',
+                pc: 2
+            })
+        );
+        assert_eq!(
+            run("1?"),
+            Err(VmError::UnknownOpcode {
+                opcode: b'?',
+                pc: 1
+            })
+        );
+    }
+
+    #[test]
+    fn call_captured() {
+        // The block "1p" starts 2 bytes after its '[' and is called twice
+        assert_eq!(output("[(1p)2+d 2c 2c"), Ok("11".into()));
+        // Execution continues after the 'c' with the values the block left
+        assert_eq!(stack("[(3*)2+a 5 md a2c m2c"), vec![Data::Int(45)]);
+    }
+
+    #[test]
+    fn call_captured_errors() {
+        // The block calls itself until the call depth limit is reached
+        assert!(matches!(
+            run("[(2 4c)2 4c"),
+            Err(VmError::Instruction { message, .. }) if message.contains("call depth")
+        ));
+        assert!(matches!(run("1 1nc"), Err(VmError::Instruction { .. })));
+        assert!(matches!(run("1 'ac"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("1c"), Err(VmError::StackUnderflow { .. })));
+    }
+
+    #[test]
+    fn rot() {
+        assert_eq!(
+            stack("1 2 3r"),
+            vec![Data::Int(2), Data::Int(3), Data::Int(1)]
+        );
+        // Only the top three values move
+        assert_eq!(
+            stack("0 1 2 3r"),
+            vec![Data::Int(0), Data::Int(2), Data::Int(3), Data::Int(1)]
+        );
+        // Three rotations restore the order
+        assert_eq!(
+            stack("1 2 3rrr"),
+            vec![Data::Int(1), Data::Int(2), Data::Int(3)]
+        );
+    }
+
+    #[test]
+    fn rot_errors() {
+        let failed = |program| {
+            let (res, stack, _) = run_full(program);
+            match res {
+                Err(VmError::Instruction { message, .. }) => (message, stack),
+                res => panic!("Expected an instruction error, found {:?}", res),
+            }
+        };
+        // The values stay on the stack, the bottom value comes first in the message
+        assert_eq!(
+            failed("1 'ar"),
+            (
+                "'r' (Rot) needs three values but the stack is [Int(1), Char('a')]".into(),
+                vec![Data::Int(1), Data::Char('a')]
+            )
+        );
+        assert_eq!(
+            failed("\"x\"r"),
+            (
+                "'r' (Rot) needs three values but the stack is [Str(\"x\")]".into(),
+                vec![Data::Str("x".into())]
+            )
+        );
+        assert_eq!(
+            failed("r"),
+            (
+                "'r' (Rot) needs three values but the stack is []".into(),
+                Vec::new()
+            )
+        );
+    }
+
+    #[test]
+    fn pick() {
+        // 0k is 'd' and 1k is 'v'
+        assert_eq!(stack("1 2 0k"), stack("1 2d"));
+        assert_eq!(stack("1 2 1k"), stack("1 2v"));
+        assert_eq!(
+            stack("1 2 1k"),
+            vec![Data::Int(1), Data::Int(2), Data::Int(1)]
+        );
+        assert!(matches!(run("1 2 2k"), Err(VmError::Instruction { .. })));
+        assert!(matches!(run("1 'ak"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn roll() {
+        // 0l does nothing, 1l is 'w' and 2l is 'r'
+        assert_eq!(stack("1 2 0l"), stack("1 2"));
+        assert_eq!(stack("1 2 1l"), stack("1 2w"));
+        assert_eq!(stack("1 2 3 2l"), stack("1 2 3r"));
+        assert_eq!(
+            stack("1 2 3 4 3l"),
+            vec![Data::Int(2), Data::Int(3), Data::Int(4), Data::Int(1)]
+        );
+        assert!(matches!(run("1 2 2l"), Err(VmError::Instruction { .. })));
+        assert!(matches!(run("1 1nl"), Err(VmError::Instruction { .. })));
+    }
+
+    #[test]
+    fn depth() {
+        assert_eq!(stack("D"), vec![Data::Int(0)]);
+        assert_eq!(stack("A"), vec![Data::Int(0)]);
+        assert_eq!(
+            stack("1 2D"),
+            vec![Data::Int(1), Data::Int(2), Data::Int(2)]
+        );
+        // Each stack is counted on its own
+        assert_eq!(stack("1 2 3aaA"), vec![Data::Int(1), Data::Int(2)]);
+        assert_eq!(stack("1 2 3aaoD"), vec![Data::Int(0)]);
+    }
+
+    #[test]
+    fn clear() {
+        let stacks = |program| {
+            let mut vm = Vm::new(InstructionSet::standard(), program);
+            vm.run().unwrap();
+            let ctx = vm.get_context();
+            (ctx.stack_len(), ctx.aux_len())
+        };
+        assert_eq!(stacks("1 2 3aC"), (0, 1));
+        assert_eq!(stacks("1 2 3aaV"), (1, 0));
+        assert_eq!(stacks("1aCV"), (0, 0));
+        // Clearing empty stacks is fine
+        assert_eq!(stacks("CV"), (0, 0));
+
+        // 'e' and 'z' see the cleared stacks as empty right away
+        assert_eq!(stack("1 2Ce"), vec![Data::Bool(true)]);
+        assert_eq!(stack("1 2aVz"), vec![Data::Int(1), Data::Bool(true)]);
+        assert_eq!(stack("1 2aCz"), vec![Data::Bool(false)]);
+    }
+
+    #[test]
+    fn float_literals() {
+        assert_eq!(stack("1.5"), vec![Data::Float(1.5)]);
+        assert_eq!(stack("0.0"), vec![Data::Float(0.0)]);
+        assert_eq!(output("3.14p"), Ok("3.14".into()));
+        // A '.' without a digit after it is the next instruction, here to_str
+        assert_eq!(stack("3."), vec![Data::Str("3".into())]);
+        assert_eq!(stack("3.o"), Vec::new());
+        // EOF directly after the '.', without the conversions the '.' is no instruction
+        let mut vm = Vm::new(
+            InstructionSet::new_with(|me| {
+                me.with_base_instructions(Default::default());
+            }),
+            "3.",
+        );
+        assert_eq!(
+            vm.run(),
+            Err(VmError::UnknownOpcode {
+                opcode: b'.',
+                pc: 1
+            })
+        );
+        assert_eq!(vm.get_context().top(), Some(&Data::Int(3)));
+        assert_eq!(stack("12"), vec![Data::Int(12)]);
+    }
+
+    #[test]
+    fn print_to_output() {
+        let mut out = Vec::new();
+        {
+            let mut ctx = Context::new("").with_output(&mut out);
+            ctx.push(Data::Int(42));
+            super::print(&mut ctx).unwrap();
+            ctx.push(Data::Str("ab\n".into()));
+            super::print(&mut ctx).unwrap();
+            assert!(matches!(
+                super::print(&mut ctx),
+                Err(VmError::StackUnderflow { .. })
+            ));
+        }
+        assert_eq!(out, b"42ab\n");
+    }
+
+    #[test]
+    fn print_stack_to_output() {
+        let mut out = Vec::new();
+        {
+            let mut ctx = Context::new("").with_output(&mut out);
+            ctx.push(Data::Bool(true));
+            ctx.to_auxiliary();
+            ctx.push(Data::Int(1));
+            ctx.push(Data::Char('a'));
+            super::print_stack(&mut ctx).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Main: [\n    Char('a'),\n    Int(1),\n]\nAux: [\n    Bool(true),\n]\n"
+        );
+    }
+
+    #[test]
+    fn input_from_reader() {
+        let mut ctx = Context::new("").with_input(&b"hi\n"[..]);
+        for _ in 0..5 {
+            super::input(&mut ctx).unwrap();
+        }
+        // The end of the input is '\0'
+        assert_eq!(
+            ctx.take_stack(),
+            ['h', 'i', '\n', '\0', '\0'].map(Data::Char).to_vec()
+        );
+
+        let mut vm = Vm::new(InstructionSet::standard(), ",,~");
+        vm.get_context_mut().set_input(&b"ok"[..]);
+        vm.run().unwrap();
+        assert_eq!(vm.get_context().top(), Some(&Data::Str("ok".into())));
+    }
+
+    #[test]
+    fn int_literal_overflow() {
+        let thirty = "123456789012345678901234567890";
+        match run(thirty) {
+            Err(VmError::Instruction { message, pc: 0 }) => assert!(message.contains(thirty)),
+            res => panic!("Expected an instruction error, found {:?}", res),
+        }
+        assert!(matches!(
+            run("9223372036854775808"),
+            Err(VmError::Instruction { .. })
+        ));
+        assert_eq!(stack("9223372036854775807"), vec![Data::Int(i64::MAX)]);
+        // A Float literal can have more digits than an Int
+        assert_eq!(
+            stack(&format!("{}.5", thirty)),
+            vec![Data::Float(1.2345678901234568e29)]
+        );
+    }
+
+    #[test]
+    fn pick_depths() {
+        for depth in 1..6 {
+            let values = (0..depth)
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(" ");
+            for n in 0..depth {
+                let stack = stack(&format!("{} {}k", values, n));
+                assert_eq!(stack.len(), depth + 1);
+                assert_eq!(stack[depth], Data::Int((depth - 1 - n) as i64));
+            }
+        }
+    }
+
+    #[test]
+    fn pick_errors() {
+        let message = |program| match run(program) {
+            Err(VmError::Instruction { message, .. }) => message,
+            res => panic!("Expected an instruction error, found {:?}", res),
+        };
+        assert_eq!(
+            message("1 2 3 3k"),
+            "'k' (Pick) called with 3 but the stack holds 3 values"
+        );
+        assert_eq!(
+            message("1 2 1nk"),
+            "'k' (Pick) called with -1 but the stack holds 2 values"
+        );
+        assert_eq!(
+            message("0k"),
+            "'k' (Pick) called with 0 but the stack holds 0 values"
+        );
+        assert!(matches!(run("k"), Err(VmError::StackUnderflow { .. })));
+    }
+
+    #[test]
+    fn peek_at() {
+        let mut ctx = Context::new("");
+        assert_eq!(ctx.peek_at(0), None);
+        ctx.push(Data::Int(1));
+        ctx.push(Data::Int(2));
+        assert_eq!(ctx.peek_at(0), Some(&Data::Int(2)));
+        assert_eq!(ctx.peek_at(1), Some(&Data::Int(1)));
+        assert_eq!(ctx.peek_at(2), None);
+        assert_eq!(ctx.peek_n(0), Some(&Data::Int(2)));
+        assert_eq!(ctx.peek_n(1), Some(&Data::Int(1)));
+        assert_eq!(ctx.peek_n(2), None);
+    }
+
+    #[test]
+    fn jump() {
+        assert_eq!(output("4j9p1p"), Ok("1".into()));
+        // The last opcode of the program is a valid target
+        assert_eq!(output("1 5j9p"), Ok("1".into()));
+    }
+
+    #[test]
+    fn jump_errors() {
+        let message = |program| match run(program) {
+            Err(VmError::Instruction { message, pc }) => (message, pc),
+            res => panic!("Expected an instruction error, found {:?}", res),
+        };
+        // The program is 2 bytes long, so 2 is already past the end
+        assert_eq!(
+            message("2j"),
+            ("'j' (Jump) to 2 past the end of the program".into(), 1)
+        );
+        assert_eq!(
+            message("1nj"),
+            ("'j' (Jump) to negative address -1".into(), 2)
+        );
+        assert!(message("?>j").0.contains("past the end"));
+        assert!(message("?<j").0.contains("negative"));
+        assert!(matches!(run("'aj"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("j"), Err(VmError::StackUnderflow { .. })));
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(output("1p# 2p\n3p"), Ok("13".into()));
+        assert_eq!(output("1p# 2p #3p"), Ok("13".into()));
+    }
+
+    #[test]
+    fn block_comments() {
+        assert_eq!(output("1p#{ 2p\n 3p #}4p"), Ok("14".into()));
+        // Nested block comments and line comments inside of them
+        assert_eq!(output("1p#{ 2p #{ 3p #} 4p # 5p\n #}6p"), Ok("16".into()));
+        assert_eq!(output("#{#{#{#}#}#}1p"), Ok("1".into()));
+        // A '#' alone doesn't end a block comment
+        assert_eq!(output("#{ # } #}1p"), Ok("1".into()));
+        // An unclosed block comment runs to the end of the program
+        assert_eq!(output("1p#{ 2p #{ 3p #}"), Ok("1".into()));
+        assert_eq!(output("1p#{"), Ok("1".into()));
+    }
+
+    #[test]
+    fn char_escapes() {
+        for (escape, c) in [
+            ("n", '\n'),
+            ("t", '\t'),
+            ("r", '\r'),
+            ("0", '\0'),
+            ("\\", '\\'),
+            ("'", '\''),
+        ] {
+            assert_eq!(stack(&format!("'\\{}", escape)), vec![Data::Char(c)]);
+        }
+        assert_eq!(stack("'\\t'\\0"), vec![Data::Char('\t'), Data::Char('\0')]);
+    }
+
+    #[test]
+    fn invalid_char_escapes() {
+        match run("'\\x") {
+            Err(VmError::Instruction { message, .. }) => assert!(message.contains("\\x")),
+            res => panic!("Expected an instruction error, found {:?}", res),
+        }
+        assert!(matches!(run("'\\"), Err(VmError::Instruction { .. })));
+    }
+
+    #[test]
+    fn bool_int_coercions() {
+        assert_eq!(stack("t?I f?I"), vec![Data::Int(1), Data::Int(0)]);
+        assert_eq!(stack("0?B 1?B"), vec![Data::Bool(false), Data::Bool(true)]);
+        assert_eq!(stack("1n?B ?<?B"), vec![Data::Bool(true), Data::Bool(true)]);
+        assert_eq!(
+            stack("t?I?B f?I?B"),
+            vec![Data::Bool(true), Data::Bool(false)]
+        );
+        assert!(matches!(run("1?I"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("t?B"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("?B"), Err(VmError::StackUnderflow { .. })));
+
+        // The same results as the general conversions
+        assert_eq!(stack("t?I f?I"), stack("tT fT"));
+        assert_eq!(stack("0?B 1?B"), stack("0 0N 1 0N"));
+    }
+
+    #[test]
+    fn char_to_str() {
+        assert_eq!(stack("'a?S"), vec![Data::Str("a".into())]);
+        assert_eq!(stack("'é?S"), vec![Data::Str("é".into())]);
+        assert_eq!(stack("'\\0?S"), vec![Data::Str("\0".into())]);
+        assert_eq!(stack("'a?S"), stack("'a."));
+        assert!(matches!(run("\"a\"?S"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("1?S"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn depth_in_loops() {
+        // Move every value to the auxiliary stack until 'D' reports an empty main stack, the
+        // value '[' pushes is dropped before counting
+        assert_eq!(stack("1 2 3 4[oaD0=s]A"), vec![Data::Int(4)]);
+        assert_eq!(stack("[oD0=s]A"), vec![Data::Int(0)]);
+        // Move them back the same way with 'A', which restores the order
+        assert_eq!(stack("1 2 3 4[oaD0=s][omA0=s]"), stack("1 2 3 4"));
+        // Sum a stack of any size
+        assert_eq!(stack("3 5 7 9 0[o+D1=s]"), vec![Data::Int(24)]);
+    }
+
+    #[test]
+    fn over() {
+        assert_eq!(stack("1 2v"), stack("1 2 1"));
+        // Mixed types, the Str is cloned and the Int stays on top of it
+        assert_eq!(
+            stack("\"a\"1v"),
+            vec![Data::Str("a".into()), Data::Int(1), Data::Str("a".into())]
+        );
+        assert_eq!(
+            stack("0 'b tv"),
+            vec![
+                Data::Int(0),
+                Data::Char('b'),
+                Data::Bool(true),
+                Data::Char('b')
+            ]
+        );
+        assert!(matches!(run("1v"), Err(VmError::StackUnderflow { .. })));
+        assert!(matches!(run("v"), Err(VmError::StackUnderflow { .. })));
+    }
 }
@@ -0,0 +1,296 @@
+//! Conversions between the value types
+
+use std::num::IntErrorKind;
+
+use super::{error, invalid_operands, stack_underflow, InstructionSet};
+use crate::{Context, Data, ProgramStorage, VmResult};
+
+pub fn add_conversion_instructions<P: ProgramStorage>(instructions: &mut InstructionSet<P>) {
+    instructions.insert(b'T', to_int);
+    instructions.insert(b'F', to_float);
+    instructions.insert(b'U', ord);
+    instructions.insert(b'I', chr);
+    instructions.insert(b'$', parse_num);
+    instructions.insert(b'.', to_str);
+
+    instructions.insert_extended(b'.', format_float);
+}
+
+/// The most decimal places '?.' writes, every Float is exact with this many
+pub const MAX_PRECISION: usize = 1074;
+
+/// ('T') Pop a value and push it as Int. A Float is truncated towards zero, a Char becomes its
+/// Unicode code point and a Bool becomes 1 or 0. Ints are pushed unchanged.
+/// Exits with an error for NaN, infinities and Floats outside of the Int range.
+pub fn to_int<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Int(i)) => ctx.push(Data::Int(i)),
+        // i64::MIN is exactly representable as Float, i64::MAX rounds up to 2^63 which is
+        // already out of range
+        Some(Data::Float(f)) if f.trunc() >= i64::MIN as f64 && f.trunc() < i64::MAX as f64 => {
+            ctx.push(Data::Int(f.trunc() as i64))
+        }
+        Some(Data::Float(f)) => error(ctx, &format!("'T' (ToInt) can't convert {} to Int", f))?,
+        Some(Data::Char(c)) => ctx.push(Data::Int(c as i64)),
+        Some(Data::Bool(b)) => ctx.push(Data::Int(b as i64)),
+        v => invalid_operands(ctx, "Int, Float, Char or Bool", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('F') Pop an Int or Float and push it as Float.
+/// Ints with an absolute value above 2^53 can't be represented exactly and are rounded to the
+/// nearest Float, `?>F` pushes 2^63.
+pub fn to_float<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Int(i)) => ctx.push(Data::Float(i as f64)),
+        Some(Data::Float(f)) => ctx.push(Data::Float(f)),
+        v => invalid_operands(ctx, "Int or Float", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('U') Pop a Char and push its Unicode code point as Int, `'AU` pushes 65
+pub fn ord<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Char(c)) => ctx.push(Data::Int(c as i64)),
+        v => invalid_operands(ctx, "Char", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('I') Pop an Int and push the Char with that Unicode code point, `65I` pushes 'A'.
+/// Exits with an error for surrogates and values which are no code point.
+pub fn chr<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let i = match ctx.pop() {
+        Some(Data::Int(i)) => i,
+        v => invalid_operands(ctx, "Int", &[v])?,
+    };
+
+    match u32::try_from(i).ok().and_then(char::from_u32) {
+        Some(c) => ctx.push(Data::Char(c)),
+        None => error(ctx, &format!("'I' (Chr) {} is no valid char", i))?,
+    }
+    Ok(())
+}
+
+/// ('$') Pop a Str and push the decimal Int written in it. The number may start with a '-',
+/// `"-42"$` pushes -42. Whitespace is not skipped, `" 42"$` fails, trim the Str with 'q' first.
+/// If the Str is no Int it is parsed as Float instead, `"2.5"$` and `"1e3"$` push Floats.
+/// Exits with an error if the Str is no number or the number is out of the Int range.
+pub fn parse_num<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let s = match ctx.pop() {
+        Some(Data::Str(s)) => s,
+        v => invalid_operands(ctx, "Str", &[v])?,
+    };
+
+    match s.parse() {
+        Ok(i) => ctx.push(Data::Int(i)),
+        Err(err) if *err.kind() == IntErrorKind::InvalidDigit => match s.parse() {
+            Ok(f) => ctx.push(Data::Float(f)),
+            Err(_) => error(ctx, &format!("'$' (Parse) {:?} is no number", s))?,
+        },
+        Err(err) => error(
+            ctx,
+            &format!("'$' (Parse) can't parse {:?} as Int: {}", s, err),
+        )?,
+    }
+    Ok(())
+}
+
+/// ('.') Pop a value and push it as Str, written the same way 'p' prints it.
+/// `42.` pushes "42", a Str is pushed unchanged.
+pub fn to_str<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Str(s)) => ctx.push(Data::Str(s)),
+        Some(v) => ctx.push(Data::Str(v.to_string())),
+        None => stack_underflow(ctx)?,
+    }
+    Ok(())
+}
+
+/// ('?.') Pop an Int n and then an Int or Float and push it as Str with n decimal places.
+/// The last place is rounded, `2.345F 2?.` pushes "2.35" and `7 3?.` pushes "7.000".
+/// Exits with an error if n is negative or larger than [`MAX_PRECISION`].
+pub fn format_float<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let (precision, f) = match (ctx.pop(), ctx.pop()) {
+        (Some(Data::Int(n)), Some(Data::Float(f))) => (n, f),
+        (Some(Data::Int(n)), Some(Data::Int(i))) => (n, i as f64),
+        (a, b) => invalid_operands(ctx, "Int, Int or Float", &[a, b])?,
+    };
+
+    match usize::try_from(precision) {
+        Ok(precision) if precision <= MAX_PRECISION => {
+            ctx.push(Data::Str(format!("{:.*}", precision, f)))
+        }
+        _ => error(
+            ctx,
+            &format!(
+                "'?.' (FormatFloat) precision {} is not between 0 and {}",
+                precision, MAX_PRECISION
+            ),
+        )?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_util::{run, stack},
+        Data, VmError,
+    };
+
+    fn str(s: &str) -> Data {
+        Data::Str(s.into())
+    }
+
+    #[test]
+    fn parse_num() {
+        assert_eq!(stack("\"42\"$"), vec![Data::Int(42)]);
+        assert_eq!(stack("\"-42\"$"), vec![Data::Int(-42)]);
+        assert_eq!(
+            stack("\"-9223372036854775808\"$"),
+            vec![Data::Int(i64::MIN)]
+        );
+        assert_eq!(stack("\"9223372036854775807\"$"), vec![Data::Int(i64::MAX)]);
+        assert_eq!(stack("\"2.5\"$"), vec![Data::Float(2.5)]);
+        assert_eq!(stack("\"1e3\"$"), vec![Data::Float(1000.0)]);
+    }
+
+    #[test]
+    fn parse_num_errors() {
+        let failed = |program| matches!(run(program), Err(VmError::Instruction { .. }));
+        assert!(failed("\"\"$"));
+        assert!(failed("\" 42\"$"));
+        assert!(failed("\"42 \"$"));
+        assert!(failed("\"4 2\"$"));
+        assert!(failed("\"abc\"$"));
+        assert!(failed("\"-9223372036854775809\"$"));
+        assert!(failed("\"9223372036854775808\"$"));
+        assert!(matches!(run("42$"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn format_float() {
+        assert_eq!(stack("\"2.345\"$ 2?."), vec![str("2.35")]);
+        assert_eq!(stack("\"2.5\"$ 0?."), vec![str("2")]);
+        assert_eq!(stack("7 3?."), vec![str("7.000")]);
+        assert_eq!(stack("\"-0.5\"$ 1?."), vec![str("-0.5")]);
+        assert_eq!(stack("?i 2?."), vec![str("inf")]);
+        assert_eq!(stack("?? 2?."), vec![str("NaN")]);
+        // The smallest Float needs every place, it ends in ...625 like every power of 1/2
+        let smallest = format!("\"{:e}\"$ {}?.", f64::from_bits(1), super::MAX_PRECISION);
+        match stack(&smallest).as_slice() {
+            [Data::Str(s)] => {
+                assert_eq!(s.len(), "0.".len() + super::MAX_PRECISION);
+                assert!(s.starts_with("0.000") && s.ends_with("625"));
+            }
+            v => panic!("Expected a Str, found {:?}", v),
+        }
+    }
+
+    #[test]
+    fn format_float_errors() {
+        let failed = |program| matches!(run(program), Err(VmError::Instruction { .. }));
+        assert!(failed("1 1n?."));
+        assert!(failed("1 1075?."));
+        assert!(matches!(run("1 'a?."), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("1F 1F?."), Err(VmError::TypeMismatch { .. })));
+        // The types are listed in pop order like for every other instruction
+        assert_eq!(
+            run("\"a\" 2?."),
+            Err(VmError::TypeMismatch {
+                expected: "Int, Int or Float",
+                found: "Int, Str".into(),
+                pc: 6,
+            })
+        );
+    }
+
+    #[test]
+    fn to_int() {
+        assert_eq!(stack("42T"), vec![Data::Int(42)]);
+        // Floats are truncated towards zero
+        assert_eq!(stack("2.9T"), vec![Data::Int(2)]);
+        assert_eq!(stack("2.9nT"), vec![Data::Int(-2)]);
+        assert_eq!(stack("0.5nT"), vec![Data::Int(0)]);
+        assert_eq!(stack("'AT"), vec![Data::Int(65)]);
+        assert_eq!(stack("'€T"), vec![Data::Int(0x20AC)]);
+        assert_eq!(stack("tT fT"), vec![Data::Int(1), Data::Int(0)]);
+        // i64::MIN is exact as Float
+        assert_eq!(stack("?<FT"), vec![Data::Int(i64::MIN)]);
+    }
+
+    #[test]
+    fn to_int_errors() {
+        let failed = |program| run(program).unwrap_err();
+        assert!(matches!(failed("??T"), VmError::Instruction { .. }));
+        assert!(matches!(failed("1.0 0/T"), VmError::Instruction { .. }));
+        assert!(matches!(failed("1.0n 0/T"), VmError::Instruction { .. }));
+        // i64::MAX rounds up to 2^63 as Float which is out of range
+        assert!(matches!(failed("?>FT"), VmError::Instruction { .. }));
+        assert!(matches!(failed("?<F2*T"), VmError::Instruction { .. }));
+        assert!(matches!(failed("\"1\"T"), VmError::TypeMismatch { .. }));
+        assert!(matches!(failed("T"), VmError::StackUnderflow { .. }));
+    }
+
+    #[test]
+    fn to_float() {
+        assert_eq!(stack("42F"), vec![Data::Float(42.0)]);
+        assert_eq!(stack("42nF"), vec![Data::Float(-42.0)]);
+        assert_eq!(stack("2.5F"), vec![Data::Float(2.5)]);
+        assert_eq!(stack("?>F"), vec![Data::Float(9223372036854775808.0)]);
+        assert_eq!(stack("?<F"), vec![Data::Float(i64::MIN as f64)]);
+        // Above 2^53 Ints are rounded, 2^53 + 1 becomes 2^53
+        assert_eq!(
+            stack("9007199254740993F"),
+            vec![Data::Float(9007199254740992.0)]
+        );
+        assert!(matches!(run("'aF"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("tF"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn ord_chr() {
+        assert_eq!(stack("'AU"), vec![Data::Int(65)]);
+        assert_eq!(stack("'éU"), vec![Data::Int(0xE9)]);
+        assert_eq!(stack("'🦀U"), vec![Data::Int(0x1F980)]);
+        assert_eq!(stack("65I"), vec![Data::Char('A')]);
+        assert_eq!(stack("1114111I"), vec![Data::Char('\u{10FFFF}')]);
+        // Round trips in both directions
+        for c in ['a', '0', 'ß', '€', '日', '🦀'] {
+            assert_eq!(stack(&format!("'{}UI", c)), vec![Data::Char(c)]);
+        }
+        assert_eq!(stack("8364IU"), vec![Data::Int(8364)]);
+        // A digit value from a char
+        assert_eq!(stack("'7U'0U-"), vec![Data::Int(7)]);
+    }
+
+    #[test]
+    fn ord_chr_errors() {
+        let failed = |program| run(program).unwrap_err();
+        // Surrogates, values above the last code point and negative values
+        assert!(matches!(failed("55296I"), VmError::Instruction { .. }));
+        assert!(matches!(failed("57343I"), VmError::Instruction { .. }));
+        assert!(matches!(failed("1114112I"), VmError::Instruction { .. }));
+        assert!(matches!(failed("1nI"), VmError::Instruction { .. }));
+        assert!(matches!(failed("?>I"), VmError::Instruction { .. }));
+        assert!(matches!(failed("65U"), VmError::TypeMismatch { .. }));
+        assert!(matches!(failed("'aI"), VmError::TypeMismatch { .. }));
+    }
+
+    #[test]
+    fn parse_num_messages() {
+        let message = |program| match run(program) {
+            Err(VmError::Instruction { message, .. }) => message,
+            res => panic!("Expected an instruction error, found {:?}", res),
+        };
+        assert_eq!(message("\"4x2\"$"), "'$' (Parse) \"4x2\" is no number");
+        assert!(message("\"99999999999999999999\"$").contains("\"99999999999999999999\""));
+        assert!(message("\"-99999999999999999999\"$").contains("\"-99999999999999999999\""));
+        // Surrounding whitespace has to be trimmed with 'q' first
+        assert!(message("\" 7\"$").contains("\" 7\""));
+        assert_eq!(stack("\" \t-7\n\"q$"), vec![Data::Int(-7)]);
+    }
+}
@@ -0,0 +1,329 @@
+use super::{error, invalid_operands, InstructionSet};
+use crate::{Context, Data, ProgramStorage, VmResult};
+
+pub fn add_list_instructions<P: ProgramStorage>(instructions: &mut InstructionSet<P>) {
+    instructions.insert(b'}', stack_to_list);
+    instructions.insert(b'{', list_to_stack);
+    instructions.insert(b'E', zip_with_index);
+    instructions.insert(b'J', flatten);
+    instructions.insert(b'Q', unique);
+    instructions.insert(b'G', group_by);
+    instructions.insert(b'K', take);
+    instructions.insert(b'O', drop_n);
+    instructions.insert(b':', split_at);
+    instructions.insert(b';', merge_stacks);
+    instructions.insert(b'W', interleave);
+}
+
+/// ('}') Pop all values of the stack and push them as one List.
+/// The bottom value of the stack becomes the first element of the list.
+pub fn stack_to_list<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let list = ctx.take_stack();
+    ctx.push(Data::List(list));
+    Ok(())
+}
+
+/// ('{') Pop a List and push all of its elements, the first element is pushed first.
+/// This is the inverse of `stack_to_list`.
+pub fn list_to_stack<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::List(list)) => {
+            for val in list {
+                ctx.push(val)
+            }
+        }
+        v => invalid_operands(ctx, "List", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('E') Pop an Int n and replace each of the next n values with a List `[value, index]`.
+/// The index starts at 0 for the top value, the order of the values stays the same.
+/// Errors if n is negative or larger than the number of values on the stack.
+pub fn zip_with_index<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let n = pop_count(ctx, "'E' (ZipWithIndex)")?;
+    let values = (0..n).filter_map(|_| ctx.pop()).collect::<Vec<_>>();
+
+    for (i, val) in values.into_iter().enumerate().rev() {
+        ctx.push(Data::List(vec![val, Data::Int(i as i64)]))
+    }
+    Ok(())
+}
+
+/// ('J') Pop a List of Lists and push the concatenation of all inner Lists
+pub fn flatten<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let lists = match ctx.pop() {
+        Some(Data::List(lists)) => lists,
+        v => invalid_operands(ctx, "List", &[v])?,
+    };
+
+    let mut flat = Vec::new();
+    for list in lists {
+        match list {
+            Data::List(inner) => flat.extend(inner),
+            v => invalid_operands(ctx, "List of Lists", &[Some(v)])?,
+        }
+    }
+
+    ctx.push(Data::List(flat));
+    Ok(())
+}
+
+/// ('Q') Pop a List, remove consecutive equal elements and push the result.
+/// To remove all duplicates the List has to be sorted first.
+pub fn unique<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::List(mut list)) => {
+            list.dedup();
+            ctx.push(Data::List(list))
+        }
+        v => invalid_operands(ctx, "List", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('G') Pop an Int n and split the rest of the stack into n Lists of equal size k.
+/// The bottom k values form the first List, which is pushed first, the top k values form the
+/// last List. Errors if the stack can't be split evenly.
+pub fn group_by<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let n = match ctx.pop() {
+        Some(Data::Int(n)) if n > 0 => n as usize,
+        Some(Data::Int(n)) => error(ctx, &format!("'G' (GroupBy) called with {} groups", n))?,
+        v => invalid_operands(ctx, "Int", &[v])?,
+    };
+
+    let values = ctx.take_stack();
+    if !values.len().is_multiple_of(n) {
+        let len = values.len();
+        // Put the values back so the stack is unchanged on error
+        for val in values {
+            ctx.push(val)
+        }
+        return error(
+            ctx,
+            &format!("'G' (GroupBy) can't split {} values into {} groups", len, n),
+        );
+    }
+
+    let k = values.len() / n;
+    let mut values = values.into_iter();
+    for _ in 0..n {
+        let group = values.by_ref().take(k).collect();
+        ctx.push(Data::List(group))
+    }
+    Ok(())
+}
+
+/// ('K') Pop an Int n and push a List of copies of the top n values, the stack stays unchanged
+/// otherwise. The deepest of the n values becomes the first element.
+pub fn take<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let n = pop_count(ctx, "'K' (Take)")?;
+    let list = (0..n)
+        .rev()
+        .filter_map(|i| ctx.peek_at(i).cloned())
+        .collect();
+    ctx.push(Data::List(list));
+    Ok(())
+}
+
+/// ('O') Pop an Int n and drop the top n values
+pub fn drop_n<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let n = pop_count(ctx, "'O' (DropN)")?;
+    for _ in 0..n {
+        ctx.pop();
+    }
+    Ok(())
+}
+
+/// (':') Pop an Int n and move all values below the top n onto the auxiliary stack, only the
+/// top n values are left on the main stack. The moved values keep their order, the value
+/// directly below the kept ones becomes the top of the auxiliary stack.
+pub fn split_at<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let n = pop_count(ctx, "':' (SplitAt)")?;
+    ctx.split_to_auxiliary(n);
+    Ok(())
+}
+
+/// (';') Move all values of the auxiliary stack back below the values of the main stack.
+/// This reverses ':' if the auxiliary stack was empty before, `1 2 3 1:;` leaves `1 2 3`.
+pub fn merge_stacks<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    ctx.merge_auxiliary();
+    Ok(())
+}
+
+/// ('W') Pop values alternately from the main and the auxiliary stack, starting with the main
+/// stack, until the stack to pop from is empty. The popped values are then pushed to the main
+/// stack in the order they were popped, the remaining values of the other stack stay in place.
+/// `1 2a3 4a5 6W` leaves `1 6 4 5 2 3` on the main stack (3 on top) and an empty auxiliary stack.
+pub fn interleave<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let mut values = Vec::new();
+    while let Some(val) = ctx.pop() {
+        values.push(val);
+        match ctx.pop_aux() {
+            Some(val) => values.push(val),
+            None => break,
+        }
+    }
+
+    for val in values {
+        ctx.push(val);
+    }
+    Ok(())
+}
+
+/// Pop an Int n which has to be between 0 and the number of values left on the stack
+fn pop_count<P: ProgramStorage>(ctx: &mut Context<P>, name: &str) -> VmResult<usize> {
+    match ctx.pop() {
+        Some(Data::Int(n)) if n >= 0 && n as usize <= ctx.stack_len() => Ok(n as usize),
+        Some(Data::Int(n)) => error(
+            ctx,
+            &format!(
+                "{} called with {} but there are {} values on the stack",
+                name,
+                n,
+                ctx.stack_len()
+            ),
+        ),
+        v => invalid_operands(ctx, "Int", &[v]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_util::{run, run_full, stack},
+        Data, VmError,
+    };
+
+    fn list(values: &[i64]) -> Data {
+        Data::List(values.iter().copied().map(Data::Int).collect())
+    }
+
+    fn message(program: &str) -> String {
+        match run(program) {
+            Err(VmError::Instruction { message, .. }) => message,
+            res => panic!("Expected an instruction error, found {:?}", res),
+        }
+    }
+
+    #[test]
+    fn stack_to_list() {
+        assert_eq!(stack("1 2 3}"), vec![list(&[1, 2, 3])]);
+        assert_eq!(stack("}"), vec![list(&[])]);
+        assert_eq!(stack("1 2}}"), vec![Data::List(vec![list(&[1, 2])])]);
+    }
+
+    #[test]
+    fn list_to_stack() {
+        assert_eq!(stack("1 2 3}{"), stack("1 2 3"));
+        assert_eq!(stack("}{"), Vec::new());
+        assert!(matches!(run("1{"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn zip_with_index() {
+        assert_eq!(
+            stack("5 6 7 2E"),
+            vec![Data::Int(5), list(&[6, 1]), list(&[7, 0])]
+        );
+        assert_eq!(stack("5 0E"), vec![Data::Int(5)]);
+        assert_eq!(stack("0E"), Vec::new());
+    }
+
+    #[test]
+    fn zip_with_index_errors() {
+        assert_eq!(
+            message("1 2 3E"),
+            "'E' (ZipWithIndex) called with 3 but there are 2 values on the stack"
+        );
+        // A huge count must not try to allocate
+        assert_eq!(
+            message("999999999999999999E"),
+            "'E' (ZipWithIndex) called with 999999999999999999 but there are 0 values on the stack"
+        );
+        assert!(message("1 1nE").contains("-1"));
+        assert!(matches!(run("1 'aE"), Err(VmError::TypeMismatch { .. })));
+        // The values stay on the stack
+        assert_eq!(run_full("1 2 3E").1, vec![Data::Int(1), Data::Int(2)]);
+    }
+
+    #[test]
+    fn flatten() {
+        assert_eq!(stack("1 2 3 4 2G}J"), vec![list(&[1, 2, 3, 4])]);
+        assert_eq!(stack("}}J"), vec![list(&[])]);
+        assert_eq!(stack("}J"), vec![list(&[])]);
+        assert!(matches!(run("1 2}J"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("1J"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn unique() {
+        assert_eq!(stack("1 1 2 1 1}Q"), vec![list(&[1, 2, 1])]);
+        assert_eq!(stack("}Q"), vec![list(&[])]);
+        assert!(matches!(run("1Q"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn group_by() {
+        assert_eq!(
+            stack("1 2 3 4 5 6 3G"),
+            vec![list(&[1, 2]), list(&[3, 4]), list(&[5, 6])]
+        );
+        assert_eq!(stack("1 2 3 1G"), vec![list(&[1, 2, 3])]);
+        assert_eq!(stack("2G"), vec![list(&[]), list(&[])]);
+    }
+
+    #[test]
+    fn group_by_errors() {
+        assert_eq!(
+            message("1 2 3 2G"),
+            "'G' (GroupBy) can't split 3 values into 2 groups"
+        );
+        // The stack is unchanged on error
+        assert_eq!(run_full("1 2 3 2G").1, stack("1 2 3"));
+        assert!(message("1 0G").contains("0 groups"));
+        assert!(message("1 1nG").contains("-1 groups"));
+    }
+
+    #[test]
+    fn take() {
+        assert_eq!(
+            stack("1 2 3 2K"),
+            vec![Data::Int(1), Data::Int(2), Data::Int(3), list(&[2, 3])]
+        );
+        assert_eq!(stack("1 0K"), vec![Data::Int(1), list(&[])]);
+        assert_eq!(
+            message("1 3K"),
+            "'K' (Take) called with 3 but there are 1 values on the stack"
+        );
+    }
+
+    #[test]
+    fn drop_n() {
+        assert_eq!(stack("1 2 3 2O"), vec![Data::Int(1)]);
+        assert_eq!(stack("1 2 3 0O"), stack("1 2 3"));
+        assert_eq!(stack("1 2 3 3O"), Vec::new());
+        assert!(message("1 2O").contains("'O' (DropN) called with 2"));
+        assert!(message("1 1nO").contains("-1"));
+    }
+
+    #[test]
+    fn split_at_merge() {
+        assert_eq!(stack("1 2 3 1:"), vec![Data::Int(3)]);
+        assert_eq!(stack("1 2 3 0:"), Vec::new());
+        assert_eq!(stack("1 2 3 1:;"), stack("1 2 3"));
+        assert_eq!(stack("1 2 3 1:C;"), stack("1 2"));
+        // The value below the kept ones is the top of the auxiliary stack
+        assert_eq!(stack("1 2 3 1:Cm"), stack("2"));
+        assert!(message("1 2:").contains("':' (SplitAt) called with 2"));
+    }
+
+    #[test]
+    fn interleave() {
+        assert_eq!(stack("1 2a3 4a5 6W"), stack("1 6 4 5 2 3"));
+        // The auxiliary stack is empty after it, so 'W' again moves only one value
+        assert_eq!(stack("1 2a3 4a5 6WW"), stack("1 6 4 5 2 3"));
+        assert_eq!(stack("W"), Vec::new());
+        assert_eq!(stack("1 2W"), stack("1 2"));
+    }
+}
@@ -0,0 +1,782 @@
+use super::{error, invalid_operands, stack_underflow, InstructionSet};
+use crate::{pattern::Pattern, Context, Data, ProgramStorage, VmResult};
+
+pub fn add_string_instructions<P: ProgramStorage>(instructions: &mut InstructionSet<P>) {
+    instructions.insert(b'P', format_duration);
+    instructions.insert(b'Z', format_timestamp);
+    instructions.insert(b'M', str_match);
+    instructions.insert(b'Y', str_match_prefix);
+    instructions.insert(b'~', concat);
+    instructions.insert(b'L', str_len);
+    instructions.insert(b'B', str_byte_len);
+    instructions.insert(b'@', char_at);
+    instructions.insert(b'S', slice);
+    instructions.insert(b'H', contains);
+    instructions.insert(b'X', find);
+    instructions.insert(b'R', reverse);
+    instructions.insert(b'y', repeat);
+    instructions.insert(b'b', substr);
+    instructions.insert(b'_', lowercase);
+    instructions.insert(b'q', trim);
+    instructions.insert(b'`', trim_start);
+    instructions.insert(b'\\', trim_end);
+
+    instructions.insert_extended(b'^', uppercase);
+    instructions.insert_extended(b'|', explode);
+    instructions.insert_extended(b'&', implode);
+}
+
+/// The maximum length in bytes of a Str built by 'y', so a huge count fails instead of
+/// aborting the process when the allocation fails
+pub const MAX_REPEAT_LEN: usize = 1 << 30;
+
+const MILLIS_PER_SECOND: u64 = 1000;
+const MILLIS_PER_MINUTE: u64 = 60 * MILLIS_PER_SECOND;
+const MILLIS_PER_HOUR: u64 = 60 * MILLIS_PER_MINUTE;
+const MILLIS_PER_DAY: i64 = 24 * MILLIS_PER_HOUR as i64;
+
+/// ('P') Pop an Int of milliseconds and push it as a human readable duration Str.
+///
+/// Hours and minutes are only included if the duration reaches them, seconds are always
+/// printed with millisecond precision: `0` is "0.000s", `83456` is "1m 23.456s" and
+/// `3600000` is "1h 0m 0.000s". Negative durations are prefixed with a '-'.
+pub fn format_duration<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let millis = match ctx.pop() {
+        Some(Data::Int(i)) => i,
+        v => invalid_operands(ctx, "Int", &[v])?,
+    };
+
+    let sign = if millis < 0 { "-" } else { "" };
+    let abs = millis.unsigned_abs();
+    let hours = abs / MILLIS_PER_HOUR;
+    let minutes = abs % MILLIS_PER_HOUR / MILLIS_PER_MINUTE;
+    let seconds = abs % MILLIS_PER_MINUTE / MILLIS_PER_SECOND;
+    let rest = abs % MILLIS_PER_SECOND;
+
+    let s = if hours > 0 {
+        format!("{}{}h {}m {}.{:03}s", sign, hours, minutes, seconds, rest)
+    } else if minutes > 0 {
+        format!("{}{}m {}.{:03}s", sign, minutes, seconds, rest)
+    } else {
+        format!("{}{}.{:03}s", sign, seconds, rest)
+    };
+
+    ctx.push(Data::Str(s));
+    Ok(())
+}
+
+/// ('Z') Pop an Int of Unix milliseconds and push it as an ISO-8601 UTC timestamp Str.
+///
+/// `0` is "1970-01-01T00:00:00.000Z" and `-1` is "1969-12-31T23:59:59.999Z", timestamps before
+/// the epoch count backwards on the proleptic gregorian calendar. Years outside of 0..=9999 are
+/// written with an explicit sign (ISO-8601 expanded representation), e.g. "+10000-01-01...".
+pub fn format_timestamp<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let millis = match ctx.pop() {
+        Some(Data::Int(i)) => i,
+        v => invalid_operands(ctx, "Int", &[v])?,
+    };
+
+    let days = millis.div_euclid(MILLIS_PER_DAY);
+    let day_millis = millis.rem_euclid(MILLIS_PER_DAY) as u64;
+    let (year, month, day) = civil_from_days(days);
+
+    let year = if (0..=9999).contains(&year) {
+        format!("{:04}", year)
+    } else {
+        format!("{:+05}", year)
+    };
+
+    ctx.push(Data::Str(format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        day_millis / MILLIS_PER_HOUR,
+        day_millis % MILLIS_PER_HOUR / MILLIS_PER_MINUTE,
+        day_millis % MILLIS_PER_MINUTE / MILLIS_PER_SECOND,
+        day_millis % MILLIS_PER_SECOND,
+    )));
+    Ok(())
+}
+
+/// ('M') Pop a pattern Str and a subject Str and push wether the whole subject matches the
+/// pattern. See [`crate::pattern`] for the pattern syntax.
+pub fn str_match<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let (pattern, subject) = pop_pattern(ctx)?;
+    ctx.push(Data::Bool(pattern.matches(&subject)));
+    Ok(())
+}
+
+/// ('Y') Pop a pattern Str and a subject Str and match the pattern against the start of the
+/// subject. Pushes wether any prefix matched and then the length in chars of the longest
+/// matching prefix (0 if none matched).
+pub fn str_match_prefix<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let (pattern, subject) = pop_pattern(ctx)?;
+    let len = pattern.match_prefix(&subject);
+    ctx.push(Data::Bool(len.is_some()));
+    ctx.push(Data::Int(len.unwrap_or(0) as i64));
+    Ok(())
+}
+
+/// ('~') Pop two Strs or Chars and push their concatenation as one Str.
+/// The value below the top comes first, `"foo" 'd~` pushes "food".
+pub fn concat<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match (ctx.pop(), ctx.pop()) {
+        (Some(a @ (Data::Str(_) | Data::Char(_))), Some(b @ (Data::Str(_) | Data::Char(_)))) => {
+            ctx.push(Data::Str(format!("{}{}", b, a)))
+        }
+        (a, b) => invalid_operands(ctx, "Str/Char, Str/Char", &[a, b])?,
+    }
+    Ok(())
+}
+
+/// ('L') Pop a Str and push its length in chars, a Char has the length 1
+pub fn str_len<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Str(s)) => ctx.push(Data::Int(s.chars().count() as i64)),
+        Some(Data::Char(_)) => ctx.push(Data::Int(1)),
+        v => invalid_operands(ctx, "Str or Char", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('B') Pop a Str or Char and push its length in bytes when encoded as UTF-8
+pub fn str_byte_len<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Str(s)) => ctx.push(Data::Int(s.len() as i64)),
+        Some(Data::Char(c)) => ctx.push(Data::Int(c.len_utf8() as i64)),
+        v => invalid_operands(ctx, "Str or Char", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('@') Pop an Int index and a Str and push the Char at that index, counted in chars.
+/// Negative indices count from the end, `"abc" 1n@` pushes 'c'.
+pub fn char_at<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let (idx, s) = match (ctx.pop(), ctx.pop()) {
+        (Some(Data::Int(idx)), Some(Data::Str(s))) => (idx, s),
+        (a, b) => invalid_operands(ctx, "Int, Str", &[a, b])?,
+    };
+
+    let pos = if idx < 0 {
+        usize::try_from(idx.unsigned_abs())
+            .ok()
+            .and_then(|back| s.chars().count().checked_sub(back))
+    } else {
+        usize::try_from(idx).ok()
+    };
+
+    match pos.and_then(|pos| s.chars().nth(pos)) {
+        Some(c) => ctx.push(Data::Char(c)),
+        None => error(
+            ctx,
+            &format!("'@' (CharAt) index {} out of range for {:?}", idx, s),
+        )?,
+    }
+    Ok(())
+}
+
+/// ('S') Pop an end and a start Int and a Str and push the chars from start up to, but not
+/// including, end as Str. Like in Python negative indices count from the end and indices out
+/// of range are clamped, `"hello" 1 1nS` pushes "ell" and `"hello" 3 10S` pushes "lo".
+pub fn slice<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let (start, end, s) = match (ctx.pop(), ctx.pop(), ctx.pop()) {
+        (Some(Data::Int(end)), Some(Data::Int(start)), Some(Data::Str(s))) => (start, end, s),
+        (a, b, c) => invalid_operands(ctx, "Int, Int, Str", &[a, b, c])?,
+    };
+
+    let len = s.chars().count() as i64;
+    let clamp = |idx: i64| {
+        let idx = if idx < 0 {
+            idx.saturating_add(len)
+        } else {
+            idx
+        };
+        idx.clamp(0, len) as usize
+    };
+    let (start, end) = (clamp(start), clamp(end));
+
+    let sliced = s
+        .chars()
+        .skip(start)
+        .take(end.saturating_sub(start))
+        .collect();
+    ctx.push(Data::Str(sliced));
+    Ok(())
+}
+
+/// ('b') Pop a length and a start Int and a Str and push the `length` chars starting at the
+/// char index `start` as Str, `"héllo" 1 3b` pushes "éll".
+/// Unlike 'S' the range is not clamped, exits with an error if it is not inside the Str.
+pub fn substr<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let (start, len, s) = match (ctx.pop(), ctx.pop(), ctx.pop()) {
+        (Some(Data::Int(len)), Some(Data::Int(start)), Some(Data::Str(s))) => (start, len, s),
+        (a, b, c) => invalid_operands(ctx, "Int, Int, Str", &[a, b, c])?,
+    };
+
+    let char_count = s.chars().count() as i64;
+    if start < 0 || len < 0 || start.saturating_add(len) > char_count {
+        return error(
+            ctx,
+            &format!(
+                "'b' (Substr) range {} {} out of range for {:?}",
+                start, len, s
+            ),
+        );
+    }
+
+    let sub = s.chars().skip(start as usize).take(len as usize).collect();
+    ctx.push(Data::Str(sub));
+    Ok(())
+}
+
+/// ('?^') Pop a Str or Char and push it in uppercase as Str.
+/// The result is always a Str because uppercasing can change the length, 'ß' becomes "SS".
+pub fn uppercase<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Str(s)) => ctx.push(Data::Str(s.to_uppercase())),
+        Some(Data::Char(c)) => ctx.push(Data::Str(c.to_uppercase().collect())),
+        v => invalid_operands(ctx, "Str or Char", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('_') Pop a Str or Char and push it in lowercase as Str.
+/// The result is always a Str because lowercasing can change the length, like for '?^'.
+pub fn lowercase<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Str(s)) => ctx.push(Data::Str(s.to_lowercase())),
+        Some(Data::Char(c)) => ctx.push(Data::Str(c.to_lowercase().collect())),
+        v => invalid_operands(ctx, "Str or Char", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('?|') Pop a Str, push each of its chars and then the number of chars as Int.
+/// The first char is pushed first like for '{', so it ends up deepest and popping the chars
+/// after the count yields them from back to front. `"ab"?|` leaves `'a 'b 2` (2 on top) and
+/// `""?|` only pushes 0.
+pub fn explode<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let s = match ctx.pop() {
+        Some(Data::Str(s)) => s,
+        v => invalid_operands(ctx, "Str", &[v])?,
+    };
+
+    let mut count = 0;
+    for c in s.chars() {
+        ctx.push(Data::Char(c));
+        count += 1;
+    }
+    ctx.push(Data::Int(count));
+    Ok(())
+}
+
+/// ('?&') Pop an Int n, then n Strs or Chars and push their concatenation as one Str.
+/// This is the inverse of '?|', the deepest value comes first: `'a "bc" 'd 3?&` pushes "abcd".
+/// Nothing but n is popped if the stack is too short or holds a value of another type.
+pub fn implode<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let n = match ctx.pop() {
+        Some(Data::Int(n)) => n,
+        v => invalid_operands(ctx, "Int", &[v])?,
+    };
+
+    let n = match usize::try_from(n) {
+        Ok(n) => n,
+        Err(_) => {
+            return error(
+                ctx,
+                &format!("'?&' (Implode) called with negative count {}", n),
+            )
+        }
+    };
+    if ctx.stack_len() < n {
+        return stack_underflow(ctx);
+    }
+    if let Some(v) = (0..n)
+        .filter_map(|idx| ctx.peek_at(idx))
+        .find(|v| !matches!(v, Data::Str(_) | Data::Char(_)))
+    {
+        return invalid_operands(ctx, "Str or Char", &[Some(v.clone())]);
+    }
+
+    let mut parts = (0..n).filter_map(|_| ctx.pop()).collect::<Vec<_>>();
+    parts.reverse();
+    let s = parts.iter().map(ToString::to_string).collect::<String>();
+    ctx.push(Data::Str(s));
+    Ok(())
+}
+
+/// ('q') Pop a Str and push it without leading and trailing whitespace.
+/// Whitespace is every Unicode whitespace char, including tabs, newlines and no-break spaces.
+pub fn trim<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    map_str(ctx, str::trim)
+}
+
+/// ('`') Pop a Str and push it without leading whitespace, see 'q'
+pub fn trim_start<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    map_str(ctx, str::trim_start)
+}
+
+/// ('\\') Pop a Str and push it without trailing whitespace, see 'q'
+pub fn trim_end<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    map_str(ctx, str::trim_end)
+}
+
+/// Pop a Str and push the part of it selected by `f`
+fn map_str<P: ProgramStorage>(ctx: &mut Context<P>, f: fn(&str) -> &str) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Str(s)) => ctx.push(Data::Str(f(&s).to_owned())),
+        v => invalid_operands(ctx, "Str", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('H') Pop a needle Str and a haystack Str and push wether the haystack contains the needle.
+/// The empty Str is contained in every Str.
+pub fn contains<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let (needle, haystack) = pop_needle(ctx)?;
+    ctx.push(Data::Bool(haystack.contains(&needle)));
+    Ok(())
+}
+
+/// ('X') Pop a needle Str and a haystack Str and push the char index of the first occurrence
+/// of the needle in the haystack, or -1 if it is not contained. `"héllo" "l"X` pushes 2.
+pub fn find<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let (needle, haystack) = pop_needle(ctx)?;
+    let idx = match haystack.find(&needle) {
+        Some(byte_idx) => haystack[..byte_idx].chars().count() as i64,
+        None => -1,
+    };
+    ctx.push(Data::Int(idx));
+    Ok(())
+}
+
+/// Pop a needle Str and then the haystack Str it should be searched in
+fn pop_needle<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult<(String, String)> {
+    match (ctx.pop(), ctx.pop()) {
+        (Some(Data::Str(needle)), Some(Data::Str(haystack))) => Ok((needle, haystack)),
+        (a, b) => invalid_operands(ctx, "Str, Str", &[a, b]),
+    }
+}
+
+/// ('R') Pop a Str and push it with its chars in reverse order, a Char is pushed unchanged
+pub fn reverse<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    match ctx.pop() {
+        Some(Data::Str(s)) => ctx.push(Data::Str(s.chars().rev().collect())),
+        Some(Data::Char(c)) => ctx.push(Data::Char(c)),
+        v => invalid_operands(ctx, "Str or Char", &[v])?,
+    }
+    Ok(())
+}
+
+/// ('y') Pop an Int n and a Str and push the Str repeated n times, `"ab" 3y` pushes "ababab".
+/// Exits with an error if n is negative or the result would be longer than `MAX_REPEAT_LEN`
+/// bytes.
+pub fn repeat<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult {
+    let (n, s) = match (ctx.pop(), ctx.pop()) {
+        (Some(Data::Int(n)), Some(Data::Str(s))) => (n, s),
+        (a, b) => invalid_operands(ctx, "Int, Str", &[a, b])?,
+    };
+
+    let n = match usize::try_from(n) {
+        Ok(n) => n,
+        Err(_) => {
+            return error(
+                ctx,
+                &format!("'y' (Repeat) called with negative count {}", n),
+            )
+        }
+    };
+    match s.len().checked_mul(n).filter(|len| *len <= MAX_REPEAT_LEN) {
+        Some(_) => ctx.push(Data::Str(s.repeat(n))),
+        None => error(
+            ctx,
+            &format!(
+                "'y' (Repeat) {:?} repeated {} times is longer than {} bytes",
+                s, n, MAX_REPEAT_LEN
+            ),
+        )?,
+    }
+    Ok(())
+}
+
+/// Pop and parse a pattern and then pop the subject it should be matched against
+fn pop_pattern<P: ProgramStorage>(ctx: &mut Context<P>) -> VmResult<(Pattern, String)> {
+    match (ctx.pop(), ctx.pop()) {
+        (Some(Data::Str(pattern)), Some(Data::Str(subject))) => match Pattern::parse(&pattern) {
+            Ok(pattern) => Ok((pattern, subject)),
+            Err(err) => error(ctx, &format!("Invalid pattern {:?}: {}", pattern, err)),
+        },
+        (a, b) => invalid_operands(ctx, "Str, Str", &[a, b]),
+    }
+}
+
+/// Convert days since the Unix epoch to a (year, month, day) date of the proleptic gregorian
+/// calendar. See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_util::{run, stack},
+        Data, InstructionSet, Vm, VmError,
+    };
+
+    #[test]
+    fn repeat() {
+        assert_eq!(stack("\"ab\" 3y"), vec![Data::Str("ababab".into())]);
+        assert_eq!(stack("\"ab\" 0y"), vec![Data::Str("".into())]);
+        assert_eq!(stack("\"\" ?>y"), vec![Data::Str("".into())]);
+    }
+
+    #[test]
+    fn repeat_errors() {
+        let failed = |program| run(program).unwrap_err();
+        assert!(matches!(failed("\"ab\" 1ny"), VmError::Instruction { .. }));
+        // The length overflows usize
+        assert!(matches!(failed("\"ab\" ?>y"), VmError::Instruction { .. }));
+        // The length fits but the allocation would be huge
+        assert!(matches!(
+            failed("\"ab\" 4000000000y"),
+            VmError::Instruction { .. }
+        ));
+        assert!(matches!(
+            failed("\"ab\" \"a\"y"),
+            VmError::TypeMismatch { .. }
+        ));
+    }
+
+    fn str(s: &str) -> Data {
+        Data::Str(s.into())
+    }
+
+    #[test]
+    fn uppercase() {
+        assert_eq!(stack("\"abc\"?^"), vec![str("ABC")]);
+        assert_eq!(stack("\"Grüße\"?^"), vec![str("GRÜSSE")]);
+        assert_eq!(stack("\"αβγ\"?^"), vec![str("ΑΒΓ")]);
+        // A Char becomes a Str as it can expand
+        assert_eq!(stack("\"ß\"0@?^"), vec![str("SS")]);
+        assert!(matches!(run("1?^"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn lowercase() {
+        assert_eq!(stack("\"ABC\"_"), vec![str("abc")]);
+        assert_eq!(stack("\"ÄÖÜ\"_"), vec![str("äöü")]);
+        // A final sigma is lowercased depending on its position
+        assert_eq!(stack("\"ΟΔΟΣ\"_"), vec![str("οδος")]);
+        assert_eq!(stack("'A_"), vec![str("a")]);
+    }
+
+    #[test]
+    fn explode() {
+        assert_eq!(
+            stack("\"ab\"?|"),
+            vec![Data::Char('a'), Data::Char('b'), Data::Int(2)]
+        );
+        assert_eq!(stack("\"\"?|"), vec![Data::Int(0)]);
+        // Chars, not bytes
+        assert_eq!(
+            stack("\"é∑\"?|"),
+            vec![Data::Char('é'), Data::Char('∑'), Data::Int(2)]
+        );
+        // Popping after the count yields the chars back to front
+        assert_eq!(stack("\"ab\"?|a"), vec![Data::Char('a'), Data::Char('b')]);
+        assert!(matches!(run("'a?|"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("?|"), Err(VmError::StackUnderflow { .. })));
+    }
+
+    #[test]
+    fn implode() {
+        assert_eq!(stack("'a \"bc\" 'd 3?&"), vec![str("abcd")]);
+        assert_eq!(stack("1 0?&"), vec![Data::Int(1), str("")]);
+        // Only n values are taken
+        assert_eq!(stack("'a 'b 'c 2?&"), vec![Data::Char('a'), str("bc")]);
+        // A failed implode leaves the values on the stack
+        let mut vm = Vm::new(InstructionSet::standard(), b"'a 1 2?&" as &[u8]);
+        assert!(matches!(vm.run(), Err(VmError::TypeMismatch { .. })));
+        assert_eq!(vm.get_context().stack_len(), 2);
+        assert!(matches!(run("'a 2?&"), Err(VmError::StackUnderflow { .. })));
+        assert!(matches!(run("'a 1n?&"), Err(VmError::Instruction { .. })));
+    }
+
+    #[test]
+    fn explode_implode_round_trip() {
+        for s in ["", "a", "héllo wörld", "∑x∑"] {
+            let program = format!("\"{}\"?|?&", s);
+            assert_eq!(stack(&program), vec![str(s)]);
+        }
+    }
+
+    #[test]
+    fn format_duration() {
+        let duration = |program| stack(program).pop().unwrap();
+        assert_eq!(duration("0P"), str("0.000s"));
+        assert_eq!(duration("999P"), str("0.999s"));
+        assert_eq!(duration("83456P"), str("1m 23.456s"));
+        assert_eq!(duration("3600000P"), str("1h 0m 0.000s"));
+        assert_eq!(duration("90061001P"), str("25h 1m 1.001s"));
+        assert_eq!(duration("1500nP"), str("-1.500s"));
+        assert_eq!(duration("3600000nP"), str("-1h 0m 0.000s"));
+        assert_eq!(duration("?<P"), str("-2562047788015h 12m 55.808s"));
+        assert!(matches!(run("'aP"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn format_timestamp() {
+        let timestamp = |program| stack(program).pop().unwrap();
+        assert_eq!(timestamp("0Z"), str("1970-01-01T00:00:00.000Z"));
+        assert_eq!(timestamp("3600000Z"), str("1970-01-01T01:00:00.000Z"));
+        assert_eq!(timestamp("1nZ"), str("1969-12-31T23:59:59.999Z"));
+        // Leap day
+        assert_eq!(timestamp("951827696789Z"), str("2000-02-29T12:34:56.789Z"));
+        assert_eq!(
+            timestamp("62135596800000nZ"),
+            str("0001-01-01T00:00:00.000Z")
+        );
+        assert_eq!(
+            timestamp("62135596800001nZ"),
+            str("0000-12-31T23:59:59.999Z")
+        );
+        assert_eq!(
+            timestamp("253402300800000Z"),
+            str("+10000-01-01T00:00:00.000Z")
+        );
+        assert!(matches!(run("'aZ"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn str_match() {
+        assert_eq!(stack("\"abc\" \"a*\"M"), vec![Data::Bool(true)]);
+        assert_eq!(stack("\"abc\" \"b*\"M"), vec![Data::Bool(false)]);
+        assert_eq!(
+            stack("\"12ab\" \"[0-9]*\"Y"),
+            vec![Data::Bool(true), Data::Int(4)]
+        );
+        assert_eq!(
+            stack("\"ab\" \"[0-9]\"Y"),
+            vec![Data::Bool(false), Data::Int(0)]
+        );
+        assert!(matches!(
+            run("\"a\" \"a[b\"M"),
+            Err(VmError::Instruction { message, .. }) if message.contains("position 1")
+        ));
+        assert!(matches!(run("1 \"a\"M"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn str_len() {
+        assert_eq!(stack("\"hello\"L"), vec![Data::Int(5)]);
+        assert_eq!(stack("\"héllo\"L"), vec![Data::Int(5)]);
+        assert_eq!(stack("\"日本\"L"), vec![Data::Int(2)]);
+        assert_eq!(stack("\"\"L"), vec![Data::Int(0)]);
+        assert_eq!(stack("'éL"), vec![Data::Int(1)]);
+        assert!(matches!(run("5L"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("L"), Err(VmError::StackUnderflow { .. })));
+    }
+
+    #[test]
+    fn str_byte_len() {
+        assert_eq!(stack("\"hello\"B"), vec![Data::Int(5)]);
+        assert_eq!(stack("\"héllo\"B"), vec![Data::Int(6)]);
+        assert_eq!(stack("\"日本\"B"), vec![Data::Int(6)]);
+        assert_eq!(stack("\"\"B"), vec![Data::Int(0)]);
+        assert_eq!(stack("'aB"), vec![Data::Int(1)]);
+        assert_eq!(stack("'éB"), vec![Data::Int(2)]);
+        assert!(matches!(run("tB"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn char_at() {
+        assert_eq!(stack("\"abc\" 0@"), vec![Data::Char('a')]);
+        assert_eq!(stack("\"abc\" 2@"), vec![Data::Char('c')]);
+        // Multi-byte chars count as one position
+        assert_eq!(stack("\"héllo\" 1@"), vec![Data::Char('é')]);
+        assert_eq!(stack("\"héllo\" 2@"), vec![Data::Char('l')]);
+        assert_eq!(stack("\"日本語\" 2@"), vec![Data::Char('語')]);
+        // Negative indices count from the end
+        assert_eq!(stack("\"abc\" 1n@"), vec![Data::Char('c')]);
+        assert_eq!(stack("\"abc\" 3n@"), vec![Data::Char('a')]);
+        assert_eq!(stack("\"héllo\" 4n@"), vec![Data::Char('é')]);
+    }
+
+    #[test]
+    fn char_at_errors() {
+        let failed = |program| run(program).unwrap_err();
+        assert!(matches!(failed("\"abc\" 3@"), VmError::Instruction { .. }));
+        assert!(matches!(failed("\"abc\" 4n@"), VmError::Instruction { .. }));
+        assert!(matches!(failed("\"\" 0@"), VmError::Instruction { .. }));
+        assert!(matches!(failed("\"abc\" ?>@"), VmError::Instruction { .. }));
+        assert!(matches!(failed("\"abc\" ?<@"), VmError::Instruction { .. }));
+        // The index is counted in chars, not bytes
+        assert!(matches!(
+            failed("\"héllo\" 5@"),
+            VmError::Instruction { .. }
+        ));
+        assert!(matches!(failed("'a 0@"), VmError::TypeMismatch { .. }));
+        assert!(matches!(
+            failed("\"abc\" 'a@"),
+            VmError::TypeMismatch { .. }
+        ));
+    }
+
+    #[test]
+    fn slice() {
+        assert_eq!(stack("\"hello\" 0 5S"), vec![str("hello")]);
+        assert_eq!(stack("\"hello\" 1 4S"), vec![str("ell")]);
+        // Empty slices
+        assert_eq!(stack("\"hello\" 2 2S"), vec![str("")]);
+        assert_eq!(stack("\"hello\" 4 1S"), vec![str("")]);
+        assert_eq!(stack("\"\" 0 0S"), vec![str("")]);
+        // Negative indices count from the end
+        assert_eq!(stack("\"hello\" 1 1nS"), vec![str("ell")]);
+        assert_eq!(stack("\"hello\" 3n 5S"), vec![str("llo")]);
+        assert_eq!(stack("\"hello\" 2n 1nS"), vec![str("l")]);
+        // Out of range indices are clamped
+        assert_eq!(stack("\"hello\" 3 10S"), vec![str("lo")]);
+        assert_eq!(stack("\"hello\" 10n 2S"), vec![str("he")]);
+        assert_eq!(stack("\"hello\" ?< ?>S"), vec![str("hello")]);
+        assert_eq!(stack("\"hello\" 7 9S"), vec![str("")]);
+        // Indices count chars
+        assert_eq!(stack("\"héllo\" 1 3S"), vec![str("él")]);
+        assert_eq!(stack("\"日本語\" 1n 3S"), vec![str("語")]);
+        assert!(matches!(
+            run("\"hello\" 1S"),
+            Err(VmError::StackUnderflow { .. })
+        ));
+        assert!(matches!(
+            run("\"hello\" 'a 1S"),
+            Err(VmError::TypeMismatch { .. })
+        ));
+        assert!(matches!(run("'a 0 1S"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn contains() {
+        let contains = |program| match stack(program).as_slice() {
+            [Data::Bool(b)] => *b,
+            v => panic!("Expected one Bool, found {:?}", v),
+        };
+        assert!(contains("\"hello\" \"hello\"H"));
+        assert!(contains("\"hello\" \"ell\"H"));
+        assert!(!contains("\"hello\" \"elo\"H"));
+        assert!(!contains("\"ell\" \"hello\"H"));
+        // The empty needle is always contained
+        assert!(contains("\"hello\" \"\"H"));
+        assert!(contains("\"\" \"\"H"));
+        assert!(contains("\"grüße\" \"üß\"H"));
+        assert!(!contains("\"grüße\" \"us\"H"));
+        assert!(matches!(
+            run("\"a\" 'aH"),
+            Err(VmError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn find() {
+        assert_eq!(stack("\"hello\" \"hello\"X"), vec![Data::Int(0)]);
+        assert_eq!(stack("\"hello\" \"l\"X"), vec![Data::Int(2)]);
+        assert_eq!(stack("\"hello\" \"z\"X"), vec![Data::Int(-1)]);
+        assert_eq!(stack("\"hello\" \"\"X"), vec![Data::Int(0)]);
+        // The position is counted in chars, the byte position of "lo" is 4
+        assert_eq!(stack("\"héllo\" \"lo\"X"), vec![Data::Int(3)]);
+        assert_eq!(stack("\"日本語\" \"語\"X"), vec![Data::Int(2)]);
+        assert_eq!(stack("\"日本語\" \"本語\"X"), vec![Data::Int(1)]);
+        assert!(matches!(run("\"a\"X"), Err(VmError::StackUnderflow { .. })));
+    }
+
+    #[test]
+    fn reverse() {
+        assert_eq!(stack("\"hello\"R"), vec![str("olleh")]);
+        assert_eq!(stack("\"racecar\"R"), vec![str("racecar")]);
+        assert_eq!(stack("\"\"R"), vec![str("")]);
+        // Reversing the bytes would make these invalid UTF-8
+        assert_eq!(stack("\"héllo\"R"), vec![str("olléh")]);
+        assert_eq!(stack("\"日本語\"R"), vec![str("語本日")]);
+        assert_eq!(stack("\"a🦀b\"R"), vec![str("b🦀a")]);
+        assert_eq!(stack("\"héllo\"RR"), vec![str("héllo")]);
+        assert_eq!(stack("'éR"), vec![Data::Char('é')]);
+        assert!(matches!(run("5R"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("R"), Err(VmError::StackUnderflow { .. })));
+    }
+
+    #[test]
+    fn substr() {
+        assert_eq!(stack("\"hello\" 0 5b"), vec![str("hello")]);
+        assert_eq!(stack("\"hello\" 1 3b"), vec![str("ell")]);
+        assert_eq!(stack("\"hello\" 5 0b"), vec![str("")]);
+        // Start and length count chars, 'é' is two bytes
+        assert_eq!(stack("\"héllo\" 1 3b"), vec![str("éll")]);
+        assert_eq!(stack("\"日本語\" 2 1b"), vec![str("語")]);
+        // Indexing agrees with '@' and 'L'
+        assert_eq!(
+            stack("\"héllo\" 1@ \"héllo\" 1 1b \"héllo\"L"),
+            vec![Data::Char('é'), str("é"), Data::Int(5)]
+        );
+    }
+
+    #[test]
+    fn substr_errors() {
+        let failed = |program| run(program).unwrap_err();
+        assert!(matches!(
+            failed("\"hello\" 3 3b"),
+            VmError::Instruction { .. }
+        ));
+        assert!(matches!(
+            failed("\"hello\" 6 0b"),
+            VmError::Instruction { .. }
+        ));
+        assert!(matches!(
+            failed("\"hello\" 1n 2b"),
+            VmError::Instruction { .. }
+        ));
+        assert!(matches!(
+            failed("\"hello\" 0 1nb"),
+            VmError::Instruction { .. }
+        ));
+        assert!(matches!(
+            failed("\"hello\" 1 ?>b"),
+            VmError::Instruction { .. }
+        ));
+        // Counted in bytes this would fit
+        assert!(matches!(
+            failed("\"日本語\" 1 3b"),
+            VmError::Instruction { .. }
+        ));
+        assert!(matches!(failed("'a 0 1b"), VmError::TypeMismatch { .. }));
+    }
+
+    #[test]
+    fn trim() {
+        assert_eq!(stack("\"  a b  \"q"), vec![str("a b")]);
+        assert_eq!(stack("\"\t\na b\r\n\"q"), vec![str("a b")]);
+        assert_eq!(stack("\"\u{00A0}a b\u{00A0}\"q"), vec![str("a b")]);
+        assert_eq!(stack("\"\u{3000}a\u{2003}\"q"), vec![str("a")]);
+        assert_eq!(stack("\" \t\n\u{00A0}\"q"), vec![str("")]);
+        assert_eq!(stack("\"\"q"), vec![str("")]);
+        assert!(matches!(run("' q"), Err(VmError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn trim_start_end() {
+        let s = "\"\u{00A0}\t a b \n\u{00A0}\"";
+        assert_eq!(stack(&format!("{}`", s)), vec![str("a b \n\u{00A0}")]);
+        assert_eq!(stack(&format!("{}\\", s)), vec![str("\u{00A0}\t a b")]);
+        assert_eq!(stack("\"ab\"`\"ab\"\\"), vec![str("ab"), str("ab")]);
+        assert!(matches!(run("5`"), Err(VmError::TypeMismatch { .. })));
+        assert!(matches!(run("5\\"), Err(VmError::TypeMismatch { .. })));
+    }
+}
@@ -0,0 +1,142 @@
+use crate::Data;
+
+/// The kind of side effect recorded in a [`JournalEntry`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum JournalKind {
+    /// A value was printed to the output
+    Print,
+    /// The whole stack was printed to the output
+    PrintStack,
+    /// A value was read from the input
+    Input,
+    /// The program requested to exit
+    Exit,
+    /// A side effect of a host defined instruction, see [`crate::Context::journal_custom`]
+    Custom(String),
+}
+
+/// One side effecting operation performed by a program
+#[derive(Clone, Debug, PartialEq)]
+pub struct JournalEntry {
+    /// Position of the entry in the journal, keeps counting after the journal is cleared
+    pub seq: u64,
+    /// Program counter of the instruction performing the side effect
+    pub pc: usize,
+    pub kind: JournalKind,
+    /// Snapshot of the values involved in the side effect
+    pub args: Vec<Data>,
+}
+
+/// An opt-in record of all side effects a program performed
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Journal {
+    entries: Option<Vec<JournalEntry>>,
+    next_seq: u64,
+}
+
+impl Journal {
+    pub(crate) fn enable(&mut self) {
+        self.entries.get_or_insert_with(Vec::new);
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.entries.is_some()
+    }
+
+    pub(crate) fn entries(&self) -> &[JournalEntry] {
+        self.entries.as_deref().unwrap_or_default()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        if let Some(entries) = &mut self.entries {
+            entries.clear()
+        }
+    }
+
+    /// Append an entry, does nothing if the journal is not enabled
+    pub(crate) fn record(&mut self, pc: usize, kind: JournalKind, args: Vec<Data>) {
+        if let Some(entries) = &mut self.entries {
+            entries.push(JournalEntry {
+                seq: self.next_seq,
+                pc,
+                kind,
+                args,
+            });
+            self.next_seq += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JournalEntry, JournalKind};
+    use crate::{Data, InstructionSet, Vm};
+
+    /// 'i' stands for a host instruction sending its value somewhere
+    fn instructions() -> InstructionSet<&'static str> {
+        let mut instructions = InstructionSet::standard();
+        instructions.insert(b'i', |ctx| {
+            let val = ctx.pop().unwrap();
+            ctx.journal_custom("send", vec![val]);
+            Ok(())
+        });
+        instructions
+    }
+
+    fn entry(seq: u64, pc: usize, kind: JournalKind, args: Vec<Data>) -> JournalEntry {
+        JournalEntry {
+            seq,
+            pc,
+            kind,
+            args,
+        }
+    }
+
+    /// Run `program` with the journal enabled if `journal` is set and get the journal
+    fn journal_of(program: &'static str, journal: bool) -> Vec<JournalEntry> {
+        let mut output = Vec::new();
+        let mut vm = Vm::new(instructions(), program).with_io_sandbox(b"a".to_vec(), &mut output);
+        if journal {
+            vm.get_context_mut().enable_journal();
+        }
+        vm.run().unwrap();
+        vm.get_context().journal().to_vec()
+    }
+
+    #[test]
+    fn records_in_order() {
+        assert_eq!(
+            journal_of(",p 7ihx9p", true),
+            vec![
+                entry(0, 0, JournalKind::Input, vec![Data::Char('a')]),
+                entry(1, 1, JournalKind::Print, vec![Data::Char('a')]),
+                entry(2, 4, JournalKind::Custom("send".into()), vec![Data::Int(7)]),
+                entry(3, 5, JournalKind::PrintStack, Vec::new()),
+                entry(4, 6, JournalKind::Exit, Vec::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        assert_eq!(journal_of(",p 7ihx9p", false), Vec::new());
+    }
+
+    #[test]
+    fn clear_keeps_recording() {
+        let mut output = Vec::new();
+        let mut vm = Vm::new(instructions(), "1p2p").with_io_sandbox(Vec::new(), &mut output);
+        vm.get_context_mut().enable_journal();
+        vm.run_with_limit(2).unwrap();
+        assert_eq!(vm.get_context().journal().len(), 1);
+
+        vm.get_context_mut().clear_journal();
+        assert_eq!(vm.get_context().journal(), &[]);
+        vm.run().unwrap();
+        // The sequence numbers keep counting
+        assert_eq!(
+            vm.get_context().journal(),
+            &[entry(1, 3, JournalKind::Print, vec![Data::Int(2)])]
+        );
+    }
+}
@@ -1,63 +1,413 @@
+pub mod analysis;
+pub mod authorizer;
+pub mod brackets;
+pub mod char_program;
+mod input;
 pub mod instructions;
+pub mod journal;
+pub mod literal;
+#[cfg(feature = "devtools")]
+pub mod minimize;
+pub mod output;
+pub mod pattern;
+#[cfg(feature = "serde")]
+pub mod session;
+#[cfg(test)]
+mod test_util;
+pub mod validator;
 
-pub use instructions::InstructionSet;
+pub use instructions::{InstructionSet, Syntax};
 
 use std::{
+    cell::RefCell,
+    collections::HashSet,
     fmt::{self, Display, Formatter},
+    io,
     marker::PhantomData,
+    rc::Rc,
 };
 
+use authorizer::{Authorizer, Decision};
+use brackets::{BracketError, BracketTable};
+use input::Input;
 use instructions::Instruction;
+use journal::{Journal, JournalEntry, JournalKind};
+use literal::{LiteralScanner, LiteralScannerFn, Literals};
+use output::{Output, Sanitize};
+use validator::EffectChecker;
 
 pub type Opcode = u8;
 
-#[derive(Clone)]
+/// Called before every instruction with the pc, the opcode and the context
+pub type TraceHook<'a, P> = Box<dyn FnMut(usize, Opcode, &Context<'a, P>) + 'a>;
+
 pub struct Vm<'a, P: ProgramStorage> {
-    /// All available instructions, indexed by the ascii value of its responding char.
-    /// Will be changed to a const array later.
+    /// All available instructions, indexed by the ascii value of its responding char
     instructions: InstructionSet<P>,
     ctx: Context<'a, P>,
+    /// Program counters at which `run_until_break` stops
+    breakpoints: HashSet<usize>,
+    trace_hook: Option<TraceHook<'a, P>>,
+    authorizer: Option<Box<dyn Authorizer<P> + 'a>>,
+    /// Only set if stack effect comments are checked
+    effect_checker: Option<EffectChecker>,
+    /// If the brackets of the program are matched before running it
+    bracket_checks: bool,
+    /// Set once the version header and, if enabled, the brackets of the program were checked
+    program_checked: bool,
+}
+
+/// Cloning a Vm does not clone its trace hook and authorizer, the clone has none
+impl<'a, P: ProgramStorage + Clone> Clone for Vm<'a, P> {
+    fn clone(&self) -> Self {
+        Self {
+            instructions: self.instructions.clone(),
+            ctx: self.ctx.clone(),
+            breakpoints: self.breakpoints.clone(),
+            trace_hook: None,
+            authorizer: None,
+            effect_checker: self.effect_checker.clone(),
+            bracket_checks: self.bracket_checks,
+            program_checked: self.program_checked,
+        }
+    }
+}
+
+impl<'a, P: ProgramStorage + Clone> Vm<'a, P> {
+    /// Clone the Vm and set the program counter of the clone to `pc`, this Vm is unchanged.
+    /// Both Vms run independently afterwards, except that they still share their input and
+    /// output. Like with `clone` the fork has no trace hook and authorizer.
+    pub fn fork_at(&self, pc: usize) -> Self {
+        let mut fork = self.clone();
+        fork.ctx.set_pc(pc);
+        fork
+    }
 }
 
 impl<'a, P: ProgramStorage> Vm<'a, P> {
     pub fn new(instructions: InstructionSet<P>, data: P) -> Self {
+        let mut ctx = Context::new(data);
+        ctx.syntax = *instructions.syntax();
+        ctx.literals = instructions.literals().clone();
         Self {
             instructions,
-            ctx: Context::new(data),
+            ctx,
+            breakpoints: Default::default(),
+            trace_hook: None,
+            authorizer: None,
+            effect_checker: None,
+            bracket_checks: false,
+            program_checked: false,
         }
     }
 
     pub fn with_program(self, program: P) -> Self {
         Vm {
             instructions: self.instructions,
-            ctx: self.ctx.with_program(program),
+            ctx: self.ctx.swap_program(program),
+            breakpoints: self.breakpoints,
+            trace_hook: self.trace_hook,
+            authorizer: self.authorizer,
+            effect_checker: self.effect_checker,
+            bracket_checks: self.bracket_checks,
+            // The new program can have another header
+            program_checked: false,
         }
     }
 
-    pub fn run(&mut self) {
-        while let Some(opcode) = self.ctx.program.opcode_at(self.ctx.pc) {
-            self.run_op(&opcode)
+    /// Let the reading instructions like ',' read from `input` and the printing instructions
+    /// like 'p' and 'h' append to `output` instead of using stdin and stdout.
+    /// Instructions are plain function pointers which can't capture buffers, so the sandbox is
+    /// set up on the Context which all builtin I/O instructions go through.
+    pub fn with_io_sandbox(mut self, input: Vec<u8>, output: &'a mut Vec<u8>) -> Self {
+        self.ctx.set_input(io::Cursor::new(input));
+        self.ctx.set_output(output);
+        self
+    }
+
+    /// Register `instruction` for `opcode`, replacing any instruction or literal for it.
+    /// This can also be called between steps of a running program.
+    pub fn set_instruction(&mut self, opcode: Opcode, instruction: Instruction<P>) {
+        self.instructions.insert(opcode, instruction);
+        self.ctx.literals.remove(&opcode);
+    }
+
+    /// Remove the instruction for `opcode` and return it, so it can be registered again later
+    /// with `set_instruction`. A literal with that prefix is removed as well.
+    pub fn take_instruction(&mut self, opcode: Opcode) -> Option<Instruction<P>> {
+        self.ctx.literals.remove(&opcode);
+        self.instructions.remove(&opcode)
+    }
+
+    /// Call `hook` before every executed instruction, replacing any previous hook
+    pub fn set_trace_hook(&mut self, hook: TraceHook<'a, P>) {
+        self.trace_hook = Some(hook);
+    }
+
+    /// Remove the trace hook and return it
+    pub fn take_trace_hook(&mut self) -> Option<TraceHook<'a, P>> {
+        self.trace_hook.take()
+    }
+
+    /// Ask `authorizer` before every instruction if it may run, replacing any previous one.
+    /// See [`authorizer`] for the possible decisions.
+    pub fn set_authorizer(&mut self, authorizer: impl Authorizer<P> + 'a) {
+        self.authorizer = Some(Box::new(authorizer));
+    }
+
+    /// Remove the authorizer and return it
+    pub fn take_authorizer(&mut self) -> Option<Box<dyn Authorizer<P> + 'a>> {
+        self.authorizer.take()
+    }
+
+    /// Check stack effect comments like `#( a b -- sum )#` while running, see
+    /// [`validator`]. A wrong depth change fails with `VmError::StackEffect`.
+    pub fn enable_effect_checks(&mut self) {
+        self.effect_checker.get_or_insert_with(Default::default);
+    }
+
+    /// Match the brackets of the program before running it, see [`Vm::check_brackets`].
+    /// Unmatched or crossing brackets fail with `VmError::Brackets` before the first
+    /// instruction is executed.
+    pub fn enable_bracket_checks(&mut self) {
+        self.bracket_checks = true;
+    }
+
+    /// Stop `run_until_break` before the instruction at `pc` is executed
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    /// Remove a breakpoint, returns false if there was none at `pc`
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    /// Run until the program counter reaches a breakpoint and return that pc.
+    /// Returns `None` if the program ended instead. At least one instruction is executed, so
+    /// calling this again while stopped at a breakpoint continues the program.
+    ///
+    /// Breakpoints are only hit at the start of an instruction, not inside of literals or
+    /// comments which are consumed by a single instruction.
+    pub fn run_until_break(&mut self) -> VmResult<Option<usize>> {
+        while self.step()? {
+            if self.breakpoints.contains(&self.ctx.pc) {
+                return Ok(Some(self.ctx.pc));
+            }
         }
+        Ok(None)
     }
 
-    pub fn run_op(&mut self, opcode: &u8) {
-        let instruction = self.instructions.get(opcode).unwrap_or_else(|| {
-            panic!(
-                "No instruction for {:?} at {}",
-                *opcode as char, self.ctx.pc
-            )
-        });
-        self.run_instruction(instruction);
+    pub fn run(&mut self) -> VmResult {
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Run the program with `input` as input and append everything it prints to `output`.
+    /// The previous input and output are restored afterwards, even if the program failed.
+    pub fn run_with_input_output(&mut self, input: &[u8], output: &mut Vec<u8>) -> VmResult {
+        let captured = Rc::new(RefCell::new(Vec::new()));
+        let reader = std::mem::replace(
+            &mut self.ctx.input.reader,
+            Rc::new(RefCell::new(io::Cursor::new(input.to_vec()))),
+        );
+        let writer = std::mem::replace(&mut self.ctx.output.writer, captured.clone());
+
+        let res = self.run();
+        self.ctx.input.reader = reader;
+        self.ctx.output.writer = writer;
+        output.extend_from_slice(&captured.borrow());
+        res
+    }
+
+    /// Run the program but execute at most `max_instructions` instructions.
+    /// When the limit is reached execution stops before the next instruction, so it can be
+    /// continued later with another call. If the limit is reached exactly at the end of the
+    /// program it is finished like by `run`, checking the stack effects and flushing the output.
+    pub fn run_with_limit(&mut self, max_instructions: u64) -> VmResult<RunOutcome> {
+        for _ in 0..max_instructions {
+            if !self.step()? {
+                return Ok(RunOutcome::Completed);
+            }
+        }
+
+        match self.ctx.cur_byte() {
+            Some(_) if !self.ctx.halted => Ok(RunOutcome::LimitReached),
+            _ => {
+                self.step()?;
+                Ok(RunOutcome::Completed)
+            }
+        }
+    }
+
+    /// Execute the single instruction at the program counter.
+    /// Returns false without doing anything if the program counter is past the end or the
+    /// program was halted by the authorizer or an instruction like 'x'.
+    ///
+    /// Before the first instruction the version header of the program is checked once, a
+    /// program requiring a version the instruction set doesn't support fails with
+    /// `VmError::IncompatibleVersion`. The brackets are checked then too if enabled with
+    /// [`Vm::enable_bracket_checks`].
+    pub fn step(&mut self) -> VmResult<bool> {
+        if self.ctx.halted {
+            return Ok(false);
+        }
+        if !self.program_checked {
+            self.check_version()?;
+            if self.bracket_checks {
+                self.check_brackets()?;
+            }
+            self.program_checked = true;
+        }
+
+        match self.ctx.program.opcode_at(self.ctx.pc) {
+            Some(opcode) => {
+                self.run_op(&opcode)?;
+                Ok(!self.ctx.halted)
+            }
+            None => {
+                if let Some(checker) = &mut self.effect_checker {
+                    checker.finish(&self.ctx)?;
+                }
+                self.ctx.flush_output()?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Check the version header of the program against the language version of the
+    /// instruction set. The major versions have to be equal and the minor version of the
+    /// program must not be newer. Programs without a header are always accepted, programs with
+    /// a malformed header never.
+    pub fn check_version(&self) -> VmResult {
+        let supported = self.instructions.language_version();
+        let required = match validator::parse_version_header(&self.ctx.program, &self.ctx.syntax) {
+            Ok(Some(required)) if required.0 == supported.0 && required.1 <= supported.1 => {
+                return Ok(())
+            }
+            Ok(None) => return Ok(()),
+            Ok(Some(required)) => Some(required),
+            Err(_) => None,
+        };
+
+        Err(VmError::IncompatibleVersion {
+            required,
+            supported,
+            pc: 0,
+        })
+    }
+
+    /// Match all loop and skip brackets of the program, like `]` and `(` do while running.
+    /// Fails with `VmError::Brackets` for unmatched and crossing pairs like `[(])`, for which
+    /// the scanning instructions would jump to a bracket of the wrong pair.
+    pub fn check_brackets(&self) -> VmResult<BracketTable> {
+        BracketTable::build(&self.ctx.program, &self.ctx.syntax).map_err(|error| {
+            VmError::Brackets {
+                pc: error.pc(),
+                error,
+            }
+        })
+    }
+
+    pub fn run_op(&mut self, opcode: &u8) -> VmResult {
+        if let Some(hook) = &mut self.trace_hook {
+            hook(self.ctx.pc, *opcode, &self.ctx);
+        }
+        if let Some(authorizer) = &mut self.authorizer {
+            match authorizer.allow(&self.ctx, *opcode) {
+                Decision::Allow => (),
+                Decision::Deny(reason) => {
+                    return Err(VmError::Denied {
+                        reason,
+                        pc: self.ctx.pc,
+                    })
+                }
+                Decision::Halt => {
+                    self.ctx.halt();
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(checker) = &mut self.effect_checker {
+            checker.before_instruction(&self.ctx)?;
+        }
+
+        if *opcode == self.ctx.syntax.extended {
+            self.run_extended()?
+        } else {
+            match self.instructions.get(opcode) {
+                Some(instruction) => self.run_instruction(instruction)?,
+                None => match self.instructions.get_literal(opcode) {
+                    Some(scanner) => self.ctx.run_literal(scanner)?,
+                    None => {
+                        return Err(VmError::UnknownOpcode {
+                            opcode: *opcode,
+                            pc: self.ctx.pc,
+                        })
+                    }
+                },
+            }
+        }
         //Use wrapping_add here because of jumps semantics
         self.ctx.pc = self.ctx.pc.wrapping_add(1);
+        self.ctx.return_from_finished_calls();
+        if let Some(checker) = &mut self.effect_checker {
+            checker.after_instruction(&self.ctx)?;
+        }
+        Ok(())
     }
 
     #[inline(always)]
-    pub fn run_instruction(&mut self, instruction: Instruction<P>) {
-        instruction(&mut self.ctx);
+    pub fn run_instruction(&mut self, instruction: Instruction<P>) -> VmResult {
+        instruction(&mut self.ctx)
+    }
+
+    /// Run the extended instruction named by the opcode after the prefix at the program
+    /// counter. The program counter is moved to the name first, so errors point at it.
+    fn run_extended(&mut self) -> VmResult {
+        let name = match self.ctx.program.opcode_at(self.ctx.pc + 1) {
+            Some(name) => name,
+            None => {
+                return Err(VmError::UnknownOpcode {
+                    opcode: self.ctx.syntax.extended,
+                    pc: self.ctx.pc,
+                })
+            }
+        };
+
+        self.ctx.advance();
+        match self.instructions.get_extended(&name) {
+            Some(instruction) => self.run_instruction(instruction),
+            None => Err(VmError::UnknownOpcode {
+                opcode: name,
+                pc: self.ctx.pc,
+            }),
+        }
+    }
+
+    /// Get a snapshot of the settings this Vm runs with
+    pub fn config(&self) -> RunConfig {
+        let mut breakpoints = self.breakpoints.iter().copied().collect::<Vec<_>>();
+        breakpoints.sort_unstable();
+
+        RunConfig {
+            syntax: self.ctx.syntax,
+            language_version: self.instructions.language_version(),
+            opcodes: self.instructions.opcodes(),
+            extended_names: self.instructions.extended_names(),
+            literal_prefixes: self.instructions.literal_prefixes(),
+            breakpoints,
+            max_call_depth: MAX_CALL_DEPTH,
+            journal: self.ctx.journal.is_enabled(),
+            sanitize: self.ctx.output.sanitize,
+            effect_checks: self.effect_checker.is_some(),
+            bracket_checks: self.bracket_checks,
+            trace_hook: self.trace_hook.is_some(),
+            authorizer: self.authorizer.is_some(),
+        }
     }
 
-    pub fn get_context(&self) -> &Context<P> {
+    pub fn get_context(&self) -> &Context<'a, P> {
         &self.ctx
     }
 
@@ -66,14 +416,179 @@ impl<'a, P: ProgramStorage> Vm<'a, P> {
     }
 }
 
+/// How a limited run of a program ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The program ran to its end
+    Completed,
+    /// The instruction limit was reached before the program ended
+    LimitReached,
+}
+
+/// The settings a Vm runs with, see [`Vm::config`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RunConfig {
+    pub syntax: Syntax,
+    /// The (major, minor) version the version header of programs is checked against
+    pub language_version: (u8, u8),
+    /// Opcodes with an instruction in ascending order
+    pub opcodes: Vec<Opcode>,
+    /// Names of the extended instructions in ascending order
+    pub extended_names: Vec<Opcode>,
+    /// Literal prefixes in ascending order
+    pub literal_prefixes: Vec<Opcode>,
+    /// Breakpoints in ascending order
+    pub breakpoints: Vec<usize>,
+    pub max_call_depth: usize,
+    /// If side effects are recorded in the journal
+    pub journal: bool,
+    pub sanitize: Sanitize,
+    /// If stack effect comments are checked
+    pub effect_checks: bool,
+    /// If the brackets are matched before running the program
+    pub bracket_checks: bool,
+    /// If a trace hook is installed
+    pub trace_hook: bool,
+    /// If an authorizer is installed
+    pub authorizer: bool,
+}
+
+/// An error which stopped the execution of a program.
+/// Every variant contains the program counter of the failing instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VmError {
+    /// An instruction needed more values than there were on the stack
+    StackUnderflow { pc: usize },
+    /// An instruction was called on values of the wrong type.
+    /// `found` lists the types of the popped values, the top value comes first.
+    TypeMismatch {
+        expected: &'static str,
+        found: String,
+        pc: usize,
+    },
+    /// There is no instruction for an opcode in the program
+    UnknownOpcode { opcode: Opcode, pc: usize },
+    /// An Int was divided by zero
+    DivisionByZero { pc: usize },
+    /// An instruction failed for any other reason, contains the message
+    Instruction { message: String, pc: usize },
+    /// The authorizer denied to run an instruction, contains its reason
+    Denied { reason: String, pc: usize },
+    /// A stack effect comment declared another change of the stack depth than the code did,
+    /// only checked if enabled with `Vm::enable_effect_checks`
+    StackEffect {
+        declared: i64,
+        observed: i64,
+        comment_pc: usize,
+        pc: usize,
+    },
+    /// The version header of the program requires a language version the instruction set
+    /// doesn't support. `required` is `None` if the header is malformed, like `#version 300.0#`.
+    IncompatibleVersion {
+        required: Option<(u8, u8)>,
+        supported: (u8, u8),
+        pc: usize,
+    },
+    /// The brackets of the program don't match, only checked if enabled with
+    /// `Vm::enable_bracket_checks`. `pc` is the bracket the error was found at.
+    Brackets { error: BracketError, pc: usize },
+}
+
+impl VmError {
+    /// Get the program counter of the instruction which failed
+    pub fn pc(&self) -> usize {
+        match self {
+            Self::StackUnderflow { pc }
+            | Self::TypeMismatch { pc, .. }
+            | Self::UnknownOpcode { pc, .. }
+            | Self::DivisionByZero { pc }
+            | Self::Instruction { pc, .. }
+            | Self::Denied { pc, .. }
+            | Self::StackEffect { pc, .. }
+            | Self::IncompatibleVersion { pc, .. }
+            | Self::Brackets { pc, .. } => *pc,
+        }
+    }
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StackUnderflow { pc } => write!(f, "Stack underflow at {}", pc),
+            Self::TypeMismatch {
+                expected,
+                found,
+                pc,
+            } => write!(f, "Expected {} but found {} at {}", expected, found, pc),
+            Self::UnknownOpcode { opcode, pc } => {
+                write!(f, "No instruction for {:?} at {}", *opcode as char, pc)
+            }
+            Self::DivisionByZero { pc } => write!(f, "Division by zero at {}", pc),
+            Self::Instruction { message, pc } => write!(f, "{} at {}", message, pc),
+            Self::Denied { reason, pc } => write!(f, "Denied: {} at {}", reason, pc),
+            Self::StackEffect {
+                declared,
+                observed,
+                comment_pc,
+                pc,
+            } => write!(
+                f,
+                "Stack effect declared at {} changes the depth by {} but it changed by {} at {}",
+                comment_pc, declared, observed, pc
+            ),
+            Self::IncompatibleVersion {
+                required: Some(required),
+                supported,
+                pc,
+            } => write!(
+                f,
+                "Program requires language version {}.{} but {}.{} is supported at {}",
+                required.0, required.1, supported.0, supported.1, pc
+            ),
+            Self::IncompatibleVersion {
+                required: None,
+                supported,
+                pc,
+            } => write!(
+                f,
+                "Program has a malformed version header, {}.{} is supported at {}",
+                supported.0, supported.1, pc
+            ),
+            Self::Brackets { error, .. } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+pub type VmResult<T = ()> = Result<T, VmError>;
+
+/// With the `serde` feature values are (de)serialized externally tagged, `Data::Int(5)` is
+/// `{"Int":5}` in JSON, so every type round-trips unambiguously
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Data {
     Int(i64),
     Bool(bool),
     Char(char),
     Str(String),
-    //Add float support later (. is occupied for that)
     Float(f64),
+    List(Vec<Data>),
+}
+
+impl Data {
+    /// Get the name of the type of this value, used in error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Int(_) => "Int",
+            Self::Bool(_) => "Bool",
+            Self::Char(_) => "Char",
+            Self::Str(_) => "Str",
+            Self::Float(_) => "Float",
+            Self::List(_) => "List",
+        }
+    }
 }
 
 impl Display for Data {
@@ -84,10 +599,36 @@ impl Display for Data {
             Self::Char(c) => write!(f, "{}", c),
             Self::Str(s) => write!(f, "{}", s),
             Self::Float(fl) => write!(f, "{}", fl),
+            Self::List(l) => {
+                write!(f, "[")?;
+                for (i, val) in l.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", val)?;
+                }
+                write!(f, "]")
+            }
         }
     }
 }
 
+/// The maximum number of nested subroutine calls before calling errors
+pub const MAX_CALL_DEPTH: usize = 1024;
+
+/// The (major, minor) language version of the builtin instructions, see
+/// [`validator::parse_version_header`]
+pub const LANGUAGE_VERSION: (u8, u8) = (0, 1);
+
+/// A subroutine call which is currently executed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Frame {
+    /// Program counter of the instruction which started the call
+    return_pc: usize,
+    /// Program counter directly after the called range, reaching it returns from the call
+    end_pc: usize,
+}
+
 /// A mutable Context for a program
 #[derive(Clone, Debug)]
 pub struct Context<'a, P: ProgramStorage + 'a> {
@@ -97,6 +638,18 @@ pub struct Context<'a, P: ProgramStorage + 'a> {
     auxiliary_stack: Vec<Data>,
     /// Program counter (current instruction)
     pc: usize,
+    /// Currently running subroutine calls
+    call_stack: Vec<Frame>,
+    /// Record of performed side effects, only filled if enabled
+    journal: Journal,
+    input: Input<'a>,
+    output: Output<'a>,
+    /// Opcodes used by the structural instructions while scanning the program
+    syntax: Syntax,
+    /// Literal scanners, needed to know the length of tokens
+    literals: Literals,
+    /// Set once the program was halted, the Vm doesn't run any further instruction
+    halted: bool,
     program: P,
     phantom: PhantomData<&'a mut P>,
 }
@@ -109,20 +662,40 @@ impl<'a, P: ProgramStorage> Context<'a, P> {
             stack: Default::default(),
             auxiliary_stack: Default::default(),
             pc: 0,
+            call_stack: Default::default(),
+            journal: Default::default(),
+            input: Default::default(),
+            output: Default::default(),
+            syntax: Default::default(),
+            literals: Default::default(),
+            halted: false,
             phantom: Default::default(),
         }
     }
 
-    pub fn with_program<'b, P2: ProgramStorage>(self, program: P2) -> Context<'b, P2> {
+    /// Replace the program, which can be of another type, keeping all other state
+    pub fn swap_program<P2: ProgramStorage>(self, program: P2) -> Context<'a, P2> {
         Context {
             program,
             stack: self.stack,
             auxiliary_stack: self.auxiliary_stack,
             pc: self.pc,
+            call_stack: self.call_stack,
+            journal: self.journal,
+            input: self.input,
+            output: self.output,
+            syntax: self.syntax,
+            literals: self.literals,
+            halted: self.halted,
             phantom: Default::default(),
         }
     }
 
+    #[deprecated(note = "renamed to `swap_program`")]
+    pub fn with_program<P2: ProgramStorage>(self, program: P2) -> Context<'a, P2> {
+        self.swap_program(program)
+    }
+
     /// Pop a value of the data stack
     pub fn pop(&mut self) -> Option<Data> {
         self.stack.pop()
@@ -133,11 +706,77 @@ impl<'a, P: ProgramStorage> Context<'a, P> {
         self.stack.last()
     }
 
+    /// Get a reference to the value `idx` positions below the top, `peek_at(0)` is the top
+    pub fn peek_at(&self, idx: usize) -> Option<&Data> {
+        self.stack.iter().rev().nth(idx)
+    }
+
+    /// Get a reference to the value `n` positions below the top, the same as [`Context::peek_at`]
+    pub fn peek_n(&self, n: usize) -> Option<&Data> {
+        self.peek_at(n)
+    }
+
+    /// Remove the value `idx` positions below the top, `remove_at(0)` is the same as `pop`
+    pub fn remove_at(&mut self, idx: usize) -> Option<Data> {
+        let pos = self.stack.len().checked_sub(idx + 1)?;
+        Some(self.stack.remove(pos))
+    }
+
     /// Push a value to the data stack
     pub fn push(&mut self, data: Data) {
         self.stack.push(data)
     }
 
+    /// Take all values of the data stack, the bottom value comes first
+    pub fn take_stack(&mut self) -> Vec<Data> {
+        std::mem::take(&mut self.stack)
+    }
+
+    /// Move all but the top `keep` values of the main stack onto the auxiliary stack, keeping
+    /// their order. Nothing is moved if there are at most `keep` values.
+    pub fn split_to_auxiliary(&mut self, keep: usize) {
+        let kept = self.stack.split_off(self.stack.len().saturating_sub(keep));
+        let moved = std::mem::replace(&mut self.stack, kept);
+        self.auxiliary_stack.extend(moved);
+    }
+
+    /// Move all values of the auxiliary stack below the values of the main stack, keeping
+    /// their order. This reverses `split_to_auxiliary` if the auxiliary stack was empty before.
+    pub fn merge_auxiliary(&mut self) {
+        let mut merged = std::mem::take(&mut self.auxiliary_stack);
+        merged.append(&mut self.stack);
+        self.stack = merged;
+    }
+
+    /// Remove all values of the main stack
+    pub fn clear_stack(&mut self) {
+        self.stack.clear();
+    }
+
+    /// Remove all values of the auxiliary stack
+    pub fn clear_aux(&mut self) {
+        self.auxiliary_stack.clear();
+    }
+
+    /// Hint that now is a good time to compact the memory used by the stacks.
+    /// Currently this only releases unused capacity of the stacks and of their Strs and Lists,
+    /// it never changes any values.
+    pub fn run_gc(&mut self) {
+        fn compact(values: &mut Vec<Data>) {
+            for val in values.iter_mut() {
+                match val {
+                    Data::Str(s) => s.shrink_to_fit(),
+                    Data::List(list) => compact(list),
+                    _ => (),
+                }
+            }
+            values.shrink_to_fit();
+        }
+
+        compact(&mut self.stack);
+        compact(&mut self.auxiliary_stack);
+    }
+
     /// Get the program counter (current instruction)
     pub fn get_pc(&self) -> usize {
         self.pc
@@ -148,6 +787,32 @@ impl<'a, P: ProgramStorage> Context<'a, P> {
         self.pc = pc
     }
 
+    /// Continue execution `offset` bytes after the current instruction, or before it if the
+    /// offset is negative. `jump_relative(1)` continues with the next instruction like normal,
+    /// `jump_relative(0)` runs the current instruction again.
+    /// The post-increment of the Vm is already accounted for, instructions call this instead of
+    /// `set_pc`. Fails if the target is before the start of the program.
+    pub fn jump_relative(&mut self, offset: i64) -> VmResult {
+        let target = i64::try_from(self.pc)
+            .ok()
+            .and_then(|pc| pc.checked_add(offset))
+            .filter(|target| *target >= 0);
+
+        match target {
+            Some(target) => {
+                self.pc = (target as usize).wrapping_sub(1);
+                Ok(())
+            }
+            None => Err(VmError::Instruction {
+                message: format!(
+                    "Relative jump by {} before the start of the program",
+                    offset
+                ),
+                pc: self.pc,
+            }),
+        }
+    }
+
     pub fn advance(&mut self) {
         //self.pc = self.pc.wrapping_add(1);
         self.pc += 1;
@@ -158,6 +823,70 @@ impl<'a, P: ProgramStorage> Context<'a, P> {
         self.pc = self.pc.wrapping_sub(1);
     }
 
+    /// Call the `len` bytes starting at `start` as a subroutine.
+    /// When the program counter reaches `start + len` execution returns to the instruction
+    /// after the current one.
+    pub fn call(&mut self, start: usize, len: usize) {
+        self.call_stack.push(Frame {
+            return_pc: self.pc,
+            end_pc: start.wrapping_add(len),
+        });
+        self.pc = start.wrapping_sub(1);
+    }
+
+    /// Get the number of currently running subroutine calls
+    pub fn call_depth(&self) -> usize {
+        self.call_stack.len()
+    }
+
+    fn return_from_finished_calls(&mut self) {
+        while let Some(frame) = self.call_stack.last() {
+            if frame.end_pc != self.pc {
+                break;
+            }
+            self.pc = frame.return_pc.wrapping_add(1);
+            self.call_stack.pop();
+        }
+    }
+
+    /// End the program after the current instruction without an error.
+    /// Instructions call this instead of exiting the process, so the host keeps running.
+    pub fn halt(&mut self) {
+        self.halted = true;
+    }
+
+    /// Check if the program was halted
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Get the opcodes used by the structural instructions
+    pub fn syntax(&self) -> &Syntax {
+        &self.syntax
+    }
+
+    /// Get the length in bytes of the token at `pc`, which is more than 1 for literals and
+    /// extended instructions
+    pub fn token_len(&self, pc: usize) -> usize {
+        literal::token_len(&self.literals, &self.syntax, &self.program, pc)
+    }
+
+    /// Run the literal scanner for the prefix at the program counter, push its value and move
+    /// the program counter to the last byte of the literal
+    fn run_literal(&mut self, scanner: LiteralScannerFn) -> VmResult {
+        match scanner(&LiteralScanner::new(&self.program, self.pc)) {
+            Ok((data, consumed)) => {
+                self.push(data);
+                self.pc += consumed;
+                Ok(())
+            }
+            Err(err) => Err(VmError::Instruction {
+                message: err.to_string(),
+                pc: self.pc,
+            }),
+        }
+    }
+
     pub fn cur_byte(&self) -> Option<u8> {
         self.program.opcode_at(self.pc)
     }
@@ -176,6 +905,16 @@ impl<'a, P: ProgramStorage> Context<'a, P> {
         }
     }
 
+    /// Get the number of values on the main stack
+    pub fn stack_len(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Get the number of values on the auxiliary stack
+    pub fn aux_len(&self) -> usize {
+        self.auxiliary_stack.len()
+    }
+
     /// Get iterator of the stack
     pub fn stack_iter(&self) -> impl Iterator<Item = &Data> {
         self.stack.iter().rev()
@@ -189,14 +928,163 @@ impl<'a, P: ProgramStorage> Context<'a, P> {
     pub fn aux_top(&self) -> Option<&Data> {
         self.auxiliary_stack.last()
     }
+
+    /// Pop a value of the auxiliary stack
+    pub fn pop_aux(&mut self) -> Option<Data> {
+        self.auxiliary_stack.pop()
+    }
+
+    /// Let the reading instructions read from `reader` instead of stdin
+    pub fn with_input(mut self, reader: impl io::Read + 'a) -> Self {
+        self.set_input(reader);
+        self
+    }
+
+    /// Let the reading instructions read from `reader` instead of stdin.
+    /// Clones of this Context made before keep their current input.
+    pub fn set_input(&mut self, reader: impl io::Read + 'a) {
+        self.input.reader = Rc::new(RefCell::new(reader));
+    }
+
+    /// Read the next byte of the input, `None` at the end of the input.
+    /// Reading instructions should use this instead of stdin, so the host can provide the input.
+    pub fn read_input_byte(&self) -> VmResult<Option<u8>> {
+        self.input.read_byte().map_err(|err| VmError::Instruction {
+            message: format!("Failed to read input: {}", err),
+            pc: self.pc,
+        })
+    }
+
+    /// Let the printing instructions write to `writer` instead of stdout
+    pub fn with_output(mut self, writer: impl io::Write + 'a) -> Self {
+        self.set_output(writer);
+        self
+    }
+
+    /// Let the printing instructions write to `writer` instead of stdout.
+    /// Clones of this Context made before keep their current output.
+    pub fn set_output(&mut self, writer: impl io::Write + 'a) {
+        self.output.writer = Rc::new(RefCell::new(writer));
+    }
+
+    /// Write `text` to the output unchanged. Printing instructions should use this instead of
+    /// `print!`, so the host can capture the output.
+    pub fn write_output(&self, text: &str) -> VmResult {
+        self.output
+            .writer
+            .borrow_mut()
+            .write_all(text.as_bytes())
+            .or_else(|err| self.output_error(err))
+    }
+
+    /// Flush the output, called before the program exits
+    pub fn flush_output(&self) -> VmResult {
+        self.output
+            .writer
+            .borrow_mut()
+            .flush()
+            .or_else(|err| self.output_error(err))
+    }
+
+    fn output_error(&self, err: io::Error) -> VmResult {
+        Err(VmError::Instruction {
+            message: format!("Failed to write output: {}", err),
+            pc: self.pc,
+        })
+    }
+
+    /// Set how control characters are printed, see [`Sanitize`]
+    pub fn set_sanitize(&mut self, sanitize: Sanitize) {
+        self.output.sanitize = sanitize;
+    }
+
+    /// Get how control characters are printed
+    pub fn sanitize(&self) -> Sanitize {
+        self.output.sanitize
+    }
+
+    /// Start recording side effects of the program in the journal
+    pub fn enable_journal(&mut self) {
+        self.journal.enable()
+    }
+
+    /// Get all side effects recorded since the journal was enabled or last cleared
+    pub fn journal(&self) -> &[JournalEntry] {
+        self.journal.entries()
+    }
+
+    /// Remove all recorded entries, the journal stays enabled
+    pub fn clear_journal(&mut self) {
+        self.journal.clear()
+    }
+
+    /// Record a side effect of the current instruction in the journal.
+    /// Does nothing if the journal is not enabled.
+    pub fn journal_record(&mut self, kind: JournalKind, args: Vec<Data>) {
+        self.journal.record(self.pc, kind, args)
+    }
+
+    /// Record a side effect of a host defined instruction in the journal.
+    /// Does nothing if the journal is not enabled.
+    pub fn journal_custom(&mut self, kind: impl Into<String>, args: Vec<Data>) {
+        self.journal_record(JournalKind::Custom(kind.into()), args)
+    }
 }
 
 pub trait ProgramStorage {
     fn opcode_at(&self, idx: usize) -> Option<Opcode>;
+
+    /// # Safety
+    /// `idx` must be in bounds of the program.
     unsafe fn opcode_at_unchecked(&self, idx: usize) -> Opcode;
+
+    /// Copy `len` opcodes starting at `start`, the part of the range after the end of the
+    /// program is left out
+    fn copy_range(&self, start: usize, len: usize) -> Vec<Opcode> {
+        (start..start.saturating_add(len))
+            .map_while(|idx| self.opcode_at(idx))
+            .collect()
+    }
+
+    /// Get the char written at `idx` and how many opcodes it spans. The program is decoded as
+    /// UTF-8 by default, so 'é' spans two opcodes. Returns `None` after the end of the program
+    /// or if the bytes at `idx` are no valid UTF-8.
+    fn char_at(&self, idx: usize) -> Option<(char, usize)> {
+        let first = self.opcode_at(idx)?;
+        let width = match first.leading_ones() {
+            0 => return Some((first as char, 1)),
+            width @ 2..=4 => width as usize,
+            _ => return None,
+        };
+
+        let bytes = self.copy_range(idx, width);
+        let c = std::str::from_utf8(&bytes).ok()?.chars().next()?;
+        Some((c, width))
+    }
+}
+
+/// Copy the part of `start..start + len` which is in bounds of `slice`
+fn copy_slice_range(slice: &[Opcode], start: usize, len: usize) -> Vec<Opcode> {
+    let end = start.saturating_add(len).min(slice.len());
+    slice.get(start..end).unwrap_or_default().to_vec()
+}
+
+impl ProgramStorage for &[u8] {
+    fn opcode_at(&self, idx: usize) -> Option<Opcode> {
+        self.get(idx).copied()
+    }
+
+    unsafe fn opcode_at_unchecked(&self, idx: usize) -> Opcode {
+        *self.get_unchecked(idx)
+    }
+
+    fn copy_range(&self, start: usize, len: usize) -> Vec<Opcode> {
+        copy_slice_range(self, start, len)
+    }
 }
 
-impl<'a> ProgramStorage for &'a [u8] {
+/// A program with a size known at compile time, which needs no heap allocation
+impl<const N: usize> ProgramStorage for [u8; N] {
     fn opcode_at(&self, idx: usize) -> Option<Opcode> {
         self.get(idx).copied()
     }
@@ -204,6 +1092,24 @@ impl<'a> ProgramStorage for &'a [u8] {
     unsafe fn opcode_at_unchecked(&self, idx: usize) -> Opcode {
         *self.get_unchecked(idx)
     }
+
+    fn copy_range(&self, start: usize, len: usize) -> Vec<Opcode> {
+        copy_slice_range(self, start, len)
+    }
+}
+
+impl<const N: usize> ProgramStorage for &[u8; N] {
+    fn opcode_at(&self, idx: usize) -> Option<Opcode> {
+        self.get(idx).copied()
+    }
+
+    unsafe fn opcode_at_unchecked(&self, idx: usize) -> Opcode {
+        *self.get_unchecked(idx)
+    }
+
+    fn copy_range(&self, start: usize, len: usize) -> Vec<Opcode> {
+        copy_slice_range(*self, start, len)
+    }
 }
 
 pub trait ExtendableProgramStorage: ProgramStorage {
@@ -224,6 +1130,41 @@ impl ProgramStorage for Vec<u8> {
     unsafe fn opcode_at_unchecked(&self, idx: usize) -> Opcode {
         *self.get_unchecked(idx)
     }
+
+    fn copy_range(&self, start: usize, len: usize) -> Vec<Opcode> {
+        copy_slice_range(self, start, len)
+    }
+}
+
+/// The program is indexed by byte, a multi-byte UTF-8 char is multiple opcodes and every pc
+/// counts bytes. See [`char_program::CharProgram`] for programs indexed by char.
+impl ProgramStorage for &str {
+    fn opcode_at(&self, idx: usize) -> Option<Opcode> {
+        self.as_bytes().get(idx).copied()
+    }
+
+    unsafe fn opcode_at_unchecked(&self, idx: usize) -> Opcode {
+        *self.as_bytes().get_unchecked(idx)
+    }
+
+    fn copy_range(&self, start: usize, len: usize) -> Vec<Opcode> {
+        copy_slice_range(self.as_bytes(), start, len)
+    }
+}
+
+/// Indexed by byte like `&str`
+impl ProgramStorage for String {
+    fn opcode_at(&self, idx: usize) -> Option<Opcode> {
+        self.as_bytes().get(idx).copied()
+    }
+
+    unsafe fn opcode_at_unchecked(&self, idx: usize) -> Opcode {
+        *self.as_bytes().get_unchecked(idx)
+    }
+
+    fn copy_range(&self, start: usize, len: usize) -> Vec<Opcode> {
+        copy_slice_range(self.as_bytes(), start, len)
+    }
 }
 
 /* #[cfg(feature="owned_vm")]
@@ -248,3 +1189,299 @@ mod owned_vm {
 
 #[cfg(feature="owned_vm")]
 pub use owned_vm::*; */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get the main stack of `vm`, the top value comes first
+    fn stack<P: ProgramStorage>(vm: &Vm<P>) -> Vec<Data> {
+        vm.get_context().stack_iter().cloned().collect()
+    }
+
+    #[test]
+    fn config_reflects_settings() {
+        let mut vm = Vm::new(InstructionSet::standard(), "1p");
+        vm.add_breakpoint(3);
+        vm.add_breakpoint(1);
+        vm.enable_effect_checks();
+        vm.get_context_mut().enable_journal();
+        vm.get_context_mut().set_sanitize(Sanitize::Strip);
+        vm.set_authorizer(|_: &Context<&str>, _| Decision::Allow);
+
+        let config = vm.config();
+        assert_eq!(config.breakpoints, vec![1, 3]);
+        assert!(config.effect_checks && config.journal && config.authorizer);
+        assert!(!config.trace_hook);
+        assert_eq!(config.sanitize, Sanitize::Strip);
+        assert_eq!(config.syntax, Syntax::default());
+        assert_eq!(config.language_version, LANGUAGE_VERSION);
+        assert!(config.opcodes.contains(&b'p'));
+        assert!(!config.opcodes.contains(&b'1'));
+        assert!(config.literal_prefixes.contains(&b'1'));
+    }
+
+    #[test]
+    fn identical_vms_have_equal_configs() {
+        let build = || {
+            let mut vm = Vm::new(InstructionSet::standard(), "1p");
+            vm.add_breakpoint(1);
+            vm
+        };
+        assert_eq!(build().config(), build().config());
+
+        let mut other = build();
+        other.take_instruction(b'p');
+        assert_ne!(build().config(), other.config());
+    }
+
+    #[test]
+    fn version_header() {
+        let run = |program| Vm::new(InstructionSet::standard(), program).run();
+        let incompatible = |required| {
+            Err(VmError::IncompatibleVersion {
+                required,
+                supported: LANGUAGE_VERSION,
+                pc: 0,
+            })
+        };
+
+        assert_eq!(run("#version 0.1#1o"), Ok(()));
+        assert_eq!(run("#version 0.0#1o"), Ok(()));
+        assert_eq!(run("# no header #1o"), Ok(()));
+        assert_eq!(run("#version 0.2#1o"), incompatible(Some((0, 2))));
+        assert_eq!(run("#version 1.0#1o"), incompatible(Some((1, 0))));
+        assert_eq!(run("#version 300.0#1p"), incompatible(None));
+        assert_eq!(run("#version#"), incompatible(None));
+        assert_eq!(run("#version 0.1 beta#"), incompatible(None));
+    }
+
+    #[test]
+    fn version_is_checked_once() {
+        let mut vm = Vm::new(InstructionSet::standard(), "#version 0.1#zs(1a0j)");
+        vm.step().unwrap();
+
+        // The header would be rejected now, but jumping back to the start doesn't check it again
+        vm.instructions.set_language_version(1, 0);
+        vm.run().unwrap();
+        assert_eq!(vm.get_context().aux_len(), 1);
+
+        let vm = vm.with_program("#version 0.1#");
+        assert!(matches!(
+            vm.check_version(),
+            Err(VmError::IncompatibleVersion { .. })
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn config_serializes() {
+        let vm = Vm::new(InstructionSet::standard(), "1p");
+        let json = serde_json::to_value(vm.config()).unwrap();
+        assert_eq!(json["syntax"]["comment"], b'#');
+        assert_eq!(json["sanitize"], "Passthrough");
+        assert_eq!(json["max_call_depth"], MAX_CALL_DEPTH);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn data_round_trips() {
+        // Values which look the same when printed stay apart
+        let values = vec![
+            Data::Int(1),
+            Data::Float(1.0),
+            Data::Char('1'),
+            Data::Str("1".into()),
+            Data::Bool(true),
+            Data::Int(i64::MIN),
+            Data::Float(-0.5),
+            Data::Str("é\n\"".into()),
+            Data::List(vec![Data::Int(2), Data::List(Vec::new())]),
+        ];
+        let json = serde_json::to_string(&values).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<Data>>(&json).unwrap(), values);
+        assert_eq!(
+            serde_json::to_string(&values[..5]).unwrap(),
+            r#"[{"Int":1},{"Float":1.0},{"Char":"1"},{"Str":"1"},{"Bool":true}]"#
+        );
+    }
+
+    #[test]
+    fn step() {
+        let mut vm = Vm::new(InstructionSet::standard(), "12 3+");
+
+        // A literal is one step
+        assert_eq!(vm.step(), Ok(true));
+        assert_eq!(stack(&vm), vec![Data::Int(12)]);
+        assert_eq!(vm.get_context().get_pc(), 2);
+        assert_eq!(vm.step(), Ok(true));
+        assert_eq!(vm.step(), Ok(true));
+        assert_eq!(stack(&vm), vec![Data::Int(3), Data::Int(12)]);
+        assert_eq!(vm.step(), Ok(true));
+        assert_eq!(stack(&vm), vec![Data::Int(15)]);
+
+        // Past the end nothing happens
+        assert_eq!(vm.step(), Ok(false));
+        assert_eq!(vm.step(), Ok(false));
+        assert_eq!(stack(&vm), vec![Data::Int(15)]);
+    }
+
+    #[test]
+    fn run_with_limit() {
+        // '0j' jumps back to the start forever
+        let mut vm = Vm::new(InstructionSet::standard(), "1o0j");
+        assert_eq!(vm.run_with_limit(1000), Ok(RunOutcome::LimitReached));
+        // Stopped before an instruction, the stack is never left half updated
+        assert_eq!(vm.get_context().stack_len(), 0);
+        assert_eq!(vm.run_with_limit(1000), Ok(RunOutcome::LimitReached));
+
+        let mut vm = Vm::new(InstructionSet::standard(), "1 2+");
+        assert_eq!(vm.run_with_limit(2), Ok(RunOutcome::LimitReached));
+        assert_eq!(vm.get_context().stack_len(), 1);
+        // Execution continues where it stopped
+        assert_eq!(vm.run_with_limit(2), Ok(RunOutcome::Completed));
+        assert_eq!(vm.get_context().top(), Some(&Data::Int(3)));
+
+        // Exactly enough fuel completes the program
+        let mut vm = Vm::new(InstructionSet::standard(), "1 2+");
+        assert_eq!(vm.run_with_limit(4), Ok(RunOutcome::Completed));
+        let mut vm = Vm::new(InstructionSet::standard(), "1x2");
+        assert_eq!(vm.run_with_limit(2), Ok(RunOutcome::Completed));
+    }
+
+    #[test]
+    fn run_with_limit_finishes_the_program() {
+        // The comment declares one new value but the code pushes two, which is only noticed
+        // once the end of the program is reached
+        let program = "#( -- a )#1 2";
+        for fuel in [4, 5, 100] {
+            let mut vm = Vm::new(InstructionSet::standard(), program);
+            vm.enable_effect_checks();
+            assert!(
+                matches!(
+                    vm.run_with_limit(fuel),
+                    Err(VmError::StackEffect {
+                        declared: 1,
+                        observed: 2,
+                        ..
+                    })
+                ),
+                "fuel {}",
+                fuel
+            );
+        }
+
+        let mut vm = Vm::new(InstructionSet::standard(), program);
+        vm.enable_effect_checks();
+        assert_eq!(vm.run_with_limit(3), Ok(RunOutcome::LimitReached));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut vm = Vm::new(InstructionSet::standard(), "1 2+3*");
+        vm.add_breakpoint(4);
+        vm.add_breakpoint(5);
+
+        // Stops before the instruction at the breakpoint
+        assert_eq!(vm.run_until_break(), Ok(Some(4)));
+        assert_eq!(stack(&vm), vec![Data::Int(3)]);
+        assert_eq!(vm.run_until_break(), Ok(Some(5)));
+        assert_eq!(stack(&vm), vec![Data::Int(3), Data::Int(3)]);
+        assert_eq!(vm.run_until_break(), Ok(None));
+        assert_eq!(stack(&vm), vec![Data::Int(9)]);
+
+        let mut vm = Vm::new(InstructionSet::standard(), "1 2+3*");
+        vm.add_breakpoint(4);
+        assert!(vm.remove_breakpoint(4));
+        assert!(!vm.remove_breakpoint(4));
+        assert_eq!(vm.run_until_break(), Ok(None));
+    }
+
+    #[test]
+    fn str_programs() {
+        let mut vm = Vm::new(InstructionSet::standard(), "1 2+\"é\"");
+        vm.run().unwrap();
+        assert_eq!(stack(&vm), vec![Data::Str("é".into()), Data::Int(3)]);
+
+        let mut vm = Vm::new(InstructionSet::standard(), String::from("20 22+"));
+        vm.run().unwrap();
+        assert_eq!(vm.get_context().top(), Some(&Data::Int(42)));
+    }
+
+    #[test]
+    fn str_programs_are_indexed_by_byte() {
+        let program = "'é1";
+        assert_eq!(program.opcode_at(0), Some(b'\''));
+        assert_eq!(program.opcode_at(1), Some(0xC3));
+        assert_eq!(program.opcode_at(2), Some(0xA9));
+        assert_eq!(program.opcode_at(3), Some(b'1'));
+        assert_eq!(program.opcode_at(4), None);
+        assert_eq!(program.copy_range(1, 10), "é1".as_bytes());
+        assert_eq!(program.char_at(1), Some(('é', 2)));
+        assert_eq!(String::from(program).opcode_at(3), Some(b'1'));
+
+        // The pc after the char literal counts both bytes of 'é'
+        let mut vm = Vm::new(InstructionSet::standard(), "'éx");
+        assert_eq!(vm.step(), Ok(true));
+        assert_eq!(vm.get_context().get_pc(), 3);
+    }
+
+    #[test]
+    fn array_programs() {
+        let program: [u8; 8] = *b"3 4*2+p ";
+        assert_eq!(program.opcode_at(7), Some(b' '));
+        assert_eq!(program.opcode_at(8), None);
+        assert_eq!(unsafe { program.opcode_at_unchecked(2) }, b'4');
+
+        let mut out = Vec::new();
+        {
+            let mut vm =
+                Vm::new(InstructionSet::standard(), program).with_io_sandbox(Vec::new(), &mut out);
+            vm.run().unwrap();
+        }
+        assert_eq!(out, b"14");
+
+        let mut vm = Vm::new(InstructionSet::standard(), &program);
+        vm.run_with_limit(3).unwrap();
+        assert_eq!(stack(&vm), vec![Data::Int(4), Data::Int(3)]);
+    }
+
+    #[test]
+    fn run_with_input_output() {
+        let mut outer = Vec::new();
+        {
+            let mut vm = Vm::new(InstructionSet::standard(), ",p,p\"!\"p")
+                .with_io_sandbox(b"xy".to_vec(), &mut outer);
+            // The output is appended to
+            let mut output = b">".to_vec();
+            assert_eq!(vm.run_with_input_output(b"ab", &mut output), Ok(()));
+            assert_eq!(output, b">ab!");
+
+            // The sandbox is restored afterwards
+            let ctx = vm.get_context();
+            assert_eq!(ctx.read_input_byte(), Ok(Some(b'x')));
+            ctx.write_output("restored").unwrap();
+        }
+        assert_eq!(outer, b"restored");
+    }
+
+    #[test]
+    fn run_with_input_output_failing() {
+        let mut outer = Vec::new();
+        {
+            let mut vm = Vm::new(InstructionSet::standard(), ",p+")
+                .with_io_sandbox(b"xy".to_vec(), &mut outer);
+            let mut output = Vec::new();
+            assert_eq!(
+                vm.run_with_input_output(b"a", &mut output),
+                Err(VmError::StackUnderflow { pc: 2 })
+            );
+            // Everything printed before the error is kept and the I/O is restored anyway
+            assert_eq!(output, b"a");
+            let ctx = vm.get_context();
+            assert_eq!(ctx.read_input_byte(), Ok(Some(b'x')));
+            ctx.write_output("restored").unwrap();
+        }
+        assert_eq!(outer, b"restored");
+    }
+}
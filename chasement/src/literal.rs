@@ -0,0 +1,192 @@
+//! Literals are instructions which consume the bytes following their prefix opcode to build a
+//! value, like numbers or quoted chars.
+//!
+//! A literal is registered with [`crate::InstructionSet::insert_literal`] as a scanner function.
+//! The Vm runs the scanner, pushes the returned value and moves the program counter past the
+//! consumed bytes. Because the length of a literal is known without executing it, instructions
+//! like 's' can skip a whole literal instead of only its prefix.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+};
+
+use crate::{Data, Opcode, ProgramStorage, Syntax};
+
+/// Scans the bytes after a prefix and returns the value and how many bytes after the prefix
+/// belong to the literal
+pub type LiteralScannerFn = fn(&LiteralScanner) -> Result<(Data, usize), LiteralError>;
+pub(crate) type Literals = HashMap<Opcode, LiteralScannerFn, nohash::BuildNoHashHasher<Opcode>>;
+
+/// Read access to the program for a [`LiteralScannerFn`]
+pub struct LiteralScanner<'a> {
+    program: &'a dyn ProgramStorage,
+    pc: usize,
+}
+
+impl<'a> LiteralScanner<'a> {
+    pub(crate) fn new(program: &'a dyn ProgramStorage, pc: usize) -> Self {
+        Self { program, pc }
+    }
+
+    /// Get the prefix opcode which started the literal
+    pub fn prefix(&self) -> Opcode {
+        // The literal was started by executing the prefix so it can't be out of bounds
+        self.program.opcode_at(self.pc).unwrap_or_default()
+    }
+
+    /// Get the byte `idx` positions after the prefix, `get(0)` is the byte directly after it.
+    /// Returns `None` after the end of the program.
+    pub fn get(&self, idx: usize) -> Option<Opcode> {
+        self.program
+            .opcode_at(self.pc.checked_add(idx)?.checked_add(1)?)
+    }
+
+    /// Get the byte `idx` positions after the prefix or fail with `LiteralError::UnexpectedEof`
+    pub fn expect(&self, idx: usize) -> Result<Opcode, LiteralError> {
+        self.get(idx).ok_or(LiteralError::UnexpectedEof)
+    }
+
+    /// Get the char starting `idx` positions after the prefix and how many positions it spans,
+    /// see [`ProgramStorage::char_at`]. Fails with `LiteralError::UnexpectedEof` after the end
+    /// of the program and with `LiteralError::Invalid` for invalid UTF-8.
+    pub fn expect_char(&self, idx: usize) -> Result<(char, usize), LiteralError> {
+        self.expect(idx)?;
+        self.program
+            .char_at(self.pc + idx + 1)
+            .ok_or_else(|| LiteralError::Invalid("Literal is not valid UTF-8".into()))
+    }
+
+    /// Get the program counter of the prefix
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+}
+
+/// An invalid literal
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LiteralError {
+    /// The program ended before the literal was complete
+    UnexpectedEof,
+    /// The literal is malformed, contains the reason
+    Invalid(String),
+}
+
+impl Display for LiteralError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEof => write!(f, "Literal ended by EOF"),
+            Self::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for LiteralError {}
+
+/// Get the length in bytes of the token at `pc`. This is the prefix and all bytes consumed by
+/// a literal, 2 for an extended instruction or 1 for every other opcode and for invalid literals.
+pub(crate) fn token_len<P: ProgramStorage>(
+    literals: &Literals,
+    syntax: &Syntax,
+    program: &P,
+    pc: usize,
+) -> usize {
+    let op = program.opcode_at(pc);
+    if op == Some(syntax.extended) && program.opcode_at(pc + 1).is_some() {
+        return 2;
+    }
+
+    let scanner = op.and_then(|op| literals.get(&op));
+    match scanner.map(|scan| scan(&LiteralScanner::new(program, pc))) {
+        Some(Ok((_, consumed))) => consumed + 1,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LiteralError, LiteralScanner};
+    use crate::{test_util, Data, InstructionSet, Vm, VmError, VmResult};
+
+    /// ('<') Literal for an Int written as exactly two hex digits, `<ff` pushes 255
+    fn scan_hex(scanner: &LiteralScanner) -> Result<(Data, usize), LiteralError> {
+        let digits = [scanner.expect(0)?, scanner.expect(1)?];
+        let text = String::from_utf8_lossy(&digits);
+        match i64::from_str_radix(&text, 16) {
+            Ok(i) => Ok((Data::Int(i), 2)),
+            Err(_) => Err(LiteralError::Invalid(format!(
+                "Invalid hex literal {}",
+                text
+            ))),
+        }
+    }
+
+    fn hex_instructions() -> InstructionSet<&'static str> {
+        let mut instructions = InstructionSet::standard();
+        instructions.insert_literal(b'<', scan_hex);
+        instructions
+    }
+
+    fn run(program: &'static str) -> VmResult<Vec<Data>> {
+        let mut vm = Vm::new(hex_instructions(), program);
+        vm.run()?;
+        Ok(vm.get_context_mut().take_stack())
+    }
+
+    #[test]
+    fn host_defined_literal() {
+        assert_eq!(run("<ff<0a+"), Ok(vec![Data::Int(265)]));
+        // 's' skips the whole literal
+        assert_eq!(run("ts<ff1"), Ok(vec![Data::Int(1)]));
+        assert_eq!(hex_instructions().token_len(&"<ff1", 0), 3);
+        assert_eq!(hex_instructions().token_len(&"<f", 0), 1);
+
+        assert!(matches!(
+            run("<f"),
+            Err(VmError::Instruction { message, pc: 0 }) if message.contains("EOF")
+        ));
+        assert!(matches!(
+            run("1<zz"),
+            Err(VmError::Instruction { message, pc: 1 }) if message == "Invalid hex literal zz"
+        ));
+    }
+
+    #[test]
+    fn literals_and_instructions_replace_each_other() {
+        let mut instructions = hex_instructions();
+        assert!(instructions.get_literal(&b'<').is_some());
+        instructions.insert(b'<', |_| Ok(()));
+        assert!(instructions.get_literal(&b'<').is_none());
+        instructions.insert_literal(b'<', scan_hex);
+        assert!(instructions.get(&b'<').is_none());
+    }
+
+    #[test]
+    fn builtin_literals() {
+        assert_eq!(
+            test_util::stack("12 0 007"),
+            vec![Data::Int(12), Data::Int(0), Data::Int(7)]
+        );
+        assert_eq!(
+            test_util::stack("'a'\\n'\\''\\\\"),
+            vec![
+                Data::Char('a'),
+                Data::Char('\n'),
+                Data::Char('\''),
+                Data::Char('\\')
+            ]
+        );
+        assert_eq!(
+            test_util::stack("\"a\\\"b\\n\""),
+            vec![Data::Str("a\"b\n".into())]
+        );
+        assert!(matches!(
+            test_util::run("'"),
+            Err(VmError::Instruction { .. })
+        ));
+        assert!(matches!(
+            test_util::run("\"abc"),
+            Err(VmError::Instruction { .. })
+        ));
+    }
+}
@@ -0,0 +1,168 @@
+//! Reduction of programs for bug reports, only available with the `devtools` feature.
+//!
+//! [`minimize`] repeatedly removes chunks of tokens from a program (ddmin style) and keeps every
+//! removal after which the predicate still holds for the run. Chunks are aligned to tokens, so
+//! a literal like `123` or `"abc"` is either kept or removed completely.
+
+use std::mem;
+
+use crate::{Data, InstructionSet, ProgramStorage, RunOutcome, Vm, VmError};
+
+/// The result of one run of a candidate program
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunReport {
+    pub outcome: Result<RunOutcome, VmError>,
+    /// The main stack when the run ended, the bottom value comes first
+    pub stack: Vec<Data>,
+}
+
+impl RunReport {
+    /// Check if both runs ended the same way: both completed, both reached the step limit or
+    /// both failed with the same kind of error. The pcs and messages of errors may differ.
+    pub fn same_halt_kind(&self, other: &RunReport) -> bool {
+        match (&self.outcome, &other.outcome) {
+            (Ok(a), Ok(b)) => a == b,
+            (Err(a), Err(b)) => mem::discriminant(a) == mem::discriminant(b),
+            _ => false,
+        }
+    }
+}
+
+/// Run `program` for at most `max_steps` instructions
+pub fn run_report(
+    instructions: &InstructionSet<Vec<u8>>,
+    program: &[u8],
+    max_steps: u64,
+) -> RunReport {
+    let mut vm = Vm::new(instructions.clone(), program.to_vec());
+    let outcome = vm.run_with_limit(max_steps);
+    RunReport {
+        outcome,
+        stack: vm.get_context_mut().take_stack(),
+    }
+}
+
+/// Reduce `program` to a smaller program for which `predicate` still holds.
+/// Every candidate runs for at most `max_steps` instructions, so removing the exit of a loop
+/// can't hang the minimizer. If the predicate doesn't hold for `program` itself it is returned
+/// unchanged.
+///
+/// The instructions are executed for real, so `instructions` should not contain instructions
+/// with side effects like printing or reading input.
+pub fn minimize(
+    instructions: &InstructionSet<Vec<u8>>,
+    program: &[u8],
+    max_steps: u64,
+    predicate: impl Fn(&RunReport) -> bool,
+) -> Vec<u8> {
+    let holds = |candidate: &[u8]| predicate(&run_report(instructions, candidate, max_steps));
+    if !holds(program) {
+        return program.to_vec();
+    }
+
+    let mut tokens = tokenize(instructions, program);
+    let mut granularity = 2;
+    while tokens.len() >= 2 {
+        let chunk_len = tokens.len().div_ceil(granularity);
+        let reduced = (0..tokens.len()).step_by(chunk_len).find_map(|start| {
+            let mut candidate = tokens.clone();
+            candidate.drain(start..(start + chunk_len).min(tokens.len()));
+            let candidate = candidate.concat();
+            holds(&candidate).then_some(candidate)
+        });
+
+        match reduced {
+            Some(candidate) => {
+                // Removing a token can join its neighbours into a new token, e.g. `1 2` to `12`
+                tokens = tokenize(instructions, &candidate);
+                granularity = (granularity - 1).max(2);
+            }
+            None if granularity >= tokens.len() => break,
+            None => granularity = (granularity * 2).min(tokens.len()),
+        }
+    }
+
+    tokens.concat()
+}
+
+/// Split `program` into its tokens
+fn tokenize(instructions: &InstructionSet<Vec<u8>>, program: &[u8]) -> Vec<Vec<u8>> {
+    let program = program.to_vec();
+    let mut tokens = Vec::new();
+    let mut pc = 0;
+    while program.opcode_at(pc).is_some() {
+        let end = (pc + instructions.token_len(&program, pc)).min(program.len());
+        tokens.push(program[pc..end].to_vec());
+        pc = end;
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{minimize, run_report, tokenize, RunReport};
+    use crate::{Data, InstructionSet, VmError};
+
+    const MAX_STEPS: u64 = 10_000;
+
+    fn minimized(program: &str, predicate: impl Fn(&RunReport) -> bool) -> String {
+        let instructions = InstructionSet::standard();
+        String::from_utf8(minimize(
+            &instructions,
+            program.as_bytes(),
+            MAX_STEPS,
+            predicate,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn tokens_keep_literals_whole() {
+        let instructions = InstructionSet::standard();
+        let tokens = tokenize(&instructions, b"12 \"a b\"'cd+");
+        let tokens = tokens.iter().map(|t| t.as_slice()).collect::<Vec<_>>();
+        let expected: Vec<&[u8]> = vec![b"12", b" ", b"\"a b\"", b"'c", b"d", b"+"];
+        assert_eq!(tokens, expected);
+    }
+
+    #[test]
+    fn isolates_a_type_error() {
+        let program = "1 2+3 4*d+5 6-2/t 7 8*+9d*";
+        let original = run_report(&InstructionSet::standard(), program.as_bytes(), MAX_STEPS);
+        assert!(matches!(
+            original.outcome,
+            Err(VmError::TypeMismatch { .. })
+        ));
+
+        let core = minimized(program, |report| report.same_halt_kind(&original));
+        assert_eq!(core, "td*");
+    }
+
+    #[test]
+    fn isolates_an_unknown_opcode() {
+        let program = "1 2+3 4*\"i\"5 6i+7d*";
+        let core = minimized(program, |report| {
+            matches!(
+                report.outcome,
+                Err(VmError::UnknownOpcode { opcode: b'i', .. })
+            )
+        });
+        assert_eq!(core, "i");
+    }
+
+    #[test]
+    fn never_splits_literals() {
+        let program = "1 2+\"needle\"3 4*\"hay\"";
+        let core = minimized(program, |report| {
+            report.stack.contains(&Data::Str("needle".into()))
+        });
+        assert_eq!(core, "\"needle\"");
+    }
+
+    #[test]
+    fn keeps_programs_without_the_fault() {
+        let program = "1 2+";
+        let core = minimized(program, |report| report.outcome.is_err());
+        assert_eq!(core, program);
+    }
+}
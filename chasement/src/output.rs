@@ -0,0 +1,146 @@
+//! How printing instructions write their output.
+
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    fmt::{self, Debug, Formatter, Write as _},
+    io::{self, Write},
+    rc::Rc,
+};
+
+/// What happens to control characters like a bell or the escape starting an ANSI sequence when
+/// a value is printed. Newlines and tabs are always printed unchanged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Sanitize {
+    /// Print every char unchanged
+    #[default]
+    Passthrough,
+    /// Print control characters as escape like `\x1b`
+    Escape,
+    /// Leave control characters out
+    Strip,
+}
+
+impl Sanitize {
+    /// Apply the sanitization to `text`, the text is only copied if it contains a control
+    /// character which has to be changed
+    pub fn apply<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        let is_unsafe = |c: char| c.is_control() && c != '\n' && c != '\t';
+        if *self == Self::Passthrough || !text.contains(is_unsafe) {
+            return Cow::Borrowed(text);
+        }
+
+        let mut sanitized = String::with_capacity(text.len());
+        for c in text.chars() {
+            if !is_unsafe(c) {
+                sanitized.push(c);
+            } else if *self == Self::Escape {
+                // All control characters are below 0x100
+                let _ = write!(sanitized, "\\x{:02x}", c as u32);
+            }
+        }
+        Cow::Owned(sanitized)
+    }
+}
+
+/// Output settings of a Context
+#[derive(Clone)]
+pub(crate) struct Output<'a> {
+    pub(crate) sanitize: Sanitize,
+    /// Shared with all clones of the Context, so they print to the same sink
+    pub(crate) writer: Rc<RefCell<dyn Write + 'a>>,
+}
+
+impl Default for Output<'_> {
+    fn default() -> Self {
+        Self {
+            sanitize: Default::default(),
+            writer: Rc::new(RefCell::new(io::stdout())),
+        }
+    }
+}
+
+impl Debug for Output<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Output")
+            .field("sanitize", &self.sanitize)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::Sanitize;
+    use crate::{InstructionSet, Vm};
+
+    /// A bell, an ANSI sequence making the text red and a newline
+    const TEXT: &str = "ding\u{7} \u{1b}[31mred\u{1b}[0m\nnext";
+
+    #[test]
+    fn apply() {
+        assert_eq!(Sanitize::Passthrough.apply(TEXT), TEXT);
+        assert_eq!(
+            Sanitize::Escape.apply(TEXT),
+            "ding\\x07 \\x1b[31mred\\x1b[0m\nnext"
+        );
+        assert_eq!(Sanitize::Strip.apply(TEXT), "ding [31mred[0m\nnext");
+        // Newlines and tabs survive all modes
+        for sanitize in [Sanitize::Passthrough, Sanitize::Escape, Sanitize::Strip] {
+            assert_eq!(sanitize.apply("a\nb\tc\n"), "a\nb\tc\n");
+        }
+    }
+
+    #[test]
+    fn copies_only_when_needed() {
+        assert!(matches!(
+            Sanitize::Escape.apply("plain\n"),
+            Cow::Borrowed(_)
+        ));
+        assert!(matches!(
+            Sanitize::Passthrough.apply(TEXT),
+            Cow::Borrowed(_)
+        ));
+        assert!(matches!(Sanitize::Strip.apply(TEXT), Cow::Owned(_)));
+    }
+
+    #[test]
+    fn long_strs() {
+        let long = "\u{7}a".repeat(200_000);
+        assert_eq!(Sanitize::Strip.apply(&long), "a".repeat(200_000));
+        assert_eq!(Sanitize::Escape.apply(&long).len(), 5 * 200_000);
+    }
+
+    /// Run `program` with `sanitize` and get its output. `TEXT` is appended with `write_output`
+    /// afterwards, which never sanitizes.
+    fn printed(program: &str, sanitize: Sanitize) -> String {
+        let mut output = Vec::new();
+        {
+            let mut vm = Vm::new(InstructionSet::standard(), program)
+                .with_io_sandbox(Vec::new(), &mut output);
+            vm.get_context_mut().set_sanitize(sanitize);
+            vm.run().unwrap();
+            vm.get_context().write_output(TEXT).unwrap();
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn print() {
+        let program = format!("\"{}\"p'\u{7}p", TEXT);
+        assert_eq!(
+            printed(&program, Sanitize::Passthrough),
+            format!("{}\u{7}{}", TEXT, TEXT)
+        );
+        assert_eq!(
+            printed(&program, Sanitize::Escape),
+            format!("ding\\x07 \\x1b[31mred\\x1b[0m\nnext\\x07{}", TEXT)
+        );
+        assert_eq!(
+            printed(&program, Sanitize::Strip),
+            format!("ding [31mred[0m\nnext{}", TEXT)
+        );
+    }
+}
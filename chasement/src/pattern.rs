@@ -0,0 +1,278 @@
+//! Glob like patterns used by the string matching instructions.
+//!
+//! The supported syntax is:
+//! - `?` matches any single char
+//! - `*` matches any sequence of chars, including the empty one
+//! - `[abc]`, `[a-z0-9]` match one char of the class, `[!a-z]` or `[^a-z]` negate the class.
+//!   A `]` directly after the opening bracket (or negation) is part of the class.
+//! - `\x` matches `x` literally, e.g. `\*` or `\[`
+//! - Every other char matches itself
+//!
+//! Matching is done by simulating all pattern positions at once, so it runs in
+//! `O(pattern * subject)` without recursion or backtracking, even for patterns like `a*a*a*b`.
+
+use std::fmt::{self, Display, Formatter};
+
+/// A parsed pattern, see the module documentation for the syntax
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    Any,
+    Star,
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+}
+
+/// An invalid pattern, `pos` is the char index in the pattern where the error was found
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PatternError {
+    pub pos: usize,
+    pub message: &'static str,
+}
+
+impl Display for PatternError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at position {} of the pattern",
+            self.message, self.pos
+        )
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+impl Token {
+    fn matches(&self, ch: char) -> bool {
+        match self {
+            Self::Char(c) => *c == ch,
+            Self::Any | Self::Star => true,
+            Self::Class { ranges, negated } => {
+                ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&ch)) != *negated
+            }
+        }
+    }
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Result<Self, PatternError> {
+        let chars = pattern.chars().collect::<Vec<_>>();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let token = match chars[i] {
+                '?' => Token::Any,
+                // Consecutive stars are equivalent to one
+                '*' if tokens.last() == Some(&Token::Star) => {
+                    i += 1;
+                    continue;
+                }
+                '*' => Token::Star,
+                '\\' => {
+                    i += 1;
+                    match chars.get(i) {
+                        Some(c) => Token::Char(*c),
+                        None => {
+                            return Err(PatternError {
+                                pos: i - 1,
+                                message: "Escape at the end",
+                            })
+                        }
+                    }
+                }
+                '[' => {
+                    let (token, end) = parse_class(&chars, i)?;
+                    i = end;
+                    token
+                }
+                c => Token::Char(c),
+            };
+
+            tokens.push(token);
+            i += 1;
+        }
+
+        Ok(Self { tokens })
+    }
+
+    /// Check if the whole `subject` matches the pattern
+    pub fn matches(&self, subject: &str) -> bool {
+        let mut matched = false;
+        self.simulate(subject, |_, full| matched = full);
+        matched
+    }
+
+    /// Get the length in chars of the longest prefix of `subject` matching the pattern
+    pub fn match_prefix(&self, subject: &str) -> Option<usize> {
+        let mut longest = None;
+        self.simulate(subject, |len, full| {
+            if full {
+                longest = Some(len)
+            }
+        });
+        longest
+    }
+
+    /// Run the pattern over `subject`, calling `on_prefix` with the length of every prefix and
+    /// whether the pattern matches that prefix completely. Stops early once no pattern position
+    /// is alive anymore.
+    fn simulate(&self, subject: &str, mut on_prefix: impl FnMut(usize, bool)) {
+        let len = self.tokens.len();
+        let mut states = vec![false; len + 1];
+        let mut next = vec![false; len + 1];
+        states[0] = true;
+        self.close(&mut states);
+        on_prefix(0, states[len]);
+
+        for (i, ch) in subject.chars().enumerate() {
+            next.iter_mut().for_each(|s| *s = false);
+            for (pos, token) in self.tokens.iter().enumerate() {
+                if !states[pos] || !token.matches(ch) {
+                    continue;
+                }
+                match token {
+                    Token::Star => next[pos] = true,
+                    _ => next[pos + 1] = true,
+                }
+            }
+            self.close(&mut next);
+            std::mem::swap(&mut states, &mut next);
+            on_prefix(i + 1, states[len]);
+
+            if !states.contains(&true) {
+                break;
+            }
+        }
+    }
+
+    /// A star can also match nothing, so every active star activates the position after it
+    fn close(&self, states: &mut [bool]) {
+        for (pos, token) in self.tokens.iter().enumerate() {
+            if states[pos] && *token == Token::Star {
+                states[pos + 1] = true;
+            }
+        }
+    }
+}
+
+/// Parse a char class starting at the '[' at `start`.
+/// Returns the class and the index of the closing ']'.
+fn parse_class(chars: &[char], start: usize) -> Result<(Token, usize), PatternError> {
+    let unterminated = PatternError {
+        pos: start,
+        message: "Unterminated char class",
+    };
+    let mut i = start + 1;
+    let negated = matches!(chars.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut ranges = Vec::new();
+    let class_start = i;
+    loop {
+        let lo = match chars.get(i) {
+            Some(']') if i != class_start => break,
+            Some(c) => *c,
+            None => return Err(unterminated),
+        };
+
+        match (chars.get(i + 1), chars.get(i + 2)) {
+            (Some('-'), Some(hi)) if *hi != ']' => {
+                if *hi < lo {
+                    return Err(PatternError {
+                        pos: i,
+                        message: "Inverted range in char class",
+                    });
+                }
+                ranges.push((lo, *hi));
+                i += 3;
+            }
+            _ => {
+                ranges.push((lo, lo));
+                i += 1;
+            }
+        }
+    }
+
+    Ok((Token::Class { ranges, negated }, i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pattern, PatternError};
+
+    fn matches(pattern: &str, subject: &str) -> bool {
+        Pattern::parse(pattern).unwrap().matches(subject)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "ac"));
+        assert!(matches("a*", "a"));
+        assert!(matches("a*", "abcdef"));
+        assert!(matches("*c*", "abcd"));
+        assert!(!matches("*x*", "abcd"));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+        assert!(matches("**", ""));
+        // Chars, not bytes
+        assert!(matches("?", "∑"));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(matches("[0-9][0-9]", "42"));
+        assert!(!matches("[0-9]", "a"));
+        assert!(matches("[!0-9]", "a"));
+        assert!(matches("[^0-9]", "a"));
+        assert!(matches("[]a]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("\\*\\?\\[", "*?["));
+        assert!(!matches("\\*", "x"));
+    }
+
+    #[test]
+    fn match_prefix() {
+        let prefix = |pattern, subject| Pattern::parse(pattern).unwrap().match_prefix(subject);
+        assert_eq!(prefix("[0-9]*", "123abc"), Some(6));
+        assert_eq!(prefix("[0-9][0-9]", "123abc"), Some(2));
+        assert_eq!(prefix("*", "abc"), Some(3));
+        assert_eq!(prefix("x", "abc"), None);
+        assert_eq!(prefix("", "abc"), Some(0));
+    }
+
+    #[test]
+    fn pathological_patterns() {
+        // A backtracking matcher takes exponential time for this
+        let pattern = "a*".repeat(50) + "b";
+        let subject = "a".repeat(10_000);
+        assert!(!matches(&pattern, &subject));
+        assert!(matches(&pattern, &(subject.clone() + "b")));
+        assert!(matches(&"*".repeat(10_000), &subject));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        let error = |pattern| Pattern::parse(pattern).unwrap_err();
+        assert_eq!(
+            error("ab[0-9"),
+            PatternError {
+                pos: 2,
+                message: "Unterminated char class"
+            }
+        );
+        assert_eq!(error("[]").pos, 0);
+        assert_eq!(error("a[z-a]").pos, 2);
+        assert_eq!(error("abc\\").pos, 3);
+    }
+}
@@ -0,0 +1,271 @@
+//! Saving and restoring the state of an interactive session, only with the `serde` feature.
+//!
+//! A [`ReplSession`] holds everything needed to continue a program later: the program text
+//! entered so far, the program counter, both stacks and the running subroutine calls. Words
+//! defined with '[' are addresses into the program, so they stay valid as long as the program
+//! text is saved with them. Sessions are saved as JSON with a format version, which
+//! [`ReplSession::load`] checks.
+//!
+//! The instructions can't be saved. Instead the session stores the
+//! [`InstructionSet::fingerprint`] it was captured with, and [`ReplSession::restore`] reports a
+//! [`FingerprintMismatch`] when the session is continued with a different instruction set.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Data, Frame, InstructionSet, ProgramStorage, Vm};
+
+/// The version of the saved format, increased on every incompatible change
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The saved state of a Vm
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReplSession {
+    /// The [`FORMAT_VERSION`] the session was saved with
+    pub format_version: u32,
+    /// The fingerprint of the instruction set the session was captured with
+    pub fingerprint: u64,
+    /// The whole program, including the bytes which are not executed yet
+    pub program: Vec<u8>,
+    pub pc: usize,
+    /// The main stack, the bottom value comes first
+    pub stack: Vec<Data>,
+    /// The auxiliary stack, the bottom value comes first
+    pub aux_stack: Vec<Data>,
+    /// The running subroutine calls as (return pc, end pc), the outermost call comes first
+    pub calls: Vec<(usize, usize)>,
+    pub halted: bool,
+}
+
+/// Why a session could not be saved or loaded
+#[derive(Debug)]
+pub enum SessionError {
+    /// Reading or writing failed or the saved session is malformed
+    Json(serde_json::Error),
+    /// The session was saved with another format version
+    UnsupportedVersion { found: u32, supported: u32 },
+}
+
+impl Display for SessionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json(err) => write!(f, "Invalid session: {}", err),
+            Self::UnsupportedVersion { found, supported } => write!(
+                f,
+                "Session has format version {} but {} is supported",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+/// Warning of [`ReplSession::restore`], the session was captured with another opcode mapping
+/// than the one it is restored with, so the program may behave differently
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FingerprintMismatch {
+    /// The fingerprint saved in the session
+    pub saved: u64,
+    /// The fingerprint of the instruction set the session was restored with
+    pub current: u64,
+}
+
+impl Display for FingerprintMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Session was saved with instruction set {:016x} but is restored with {:016x}",
+            self.saved, self.current
+        )
+    }
+}
+
+impl From<serde_json::Error> for SessionError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+impl ReplSession {
+    /// Capture the state of `vm`. The program is saved as opcodes, so a
+    /// [`crate::char_program::CharProgram`] is restored as its opcodes.
+    pub fn capture<P: ProgramStorage>(vm: &Vm<P>) -> Self {
+        let ctx = &vm.ctx;
+        Self {
+            format_version: FORMAT_VERSION,
+            fingerprint: vm.instructions.fingerprint(),
+            program: ctx.program.copy_range(0, usize::MAX),
+            pc: ctx.pc,
+            stack: ctx.stack.clone(),
+            aux_stack: ctx.auxiliary_stack.clone(),
+            calls: ctx
+                .call_stack
+                .iter()
+                .map(|frame| (frame.return_pc, frame.end_pc))
+                .collect(),
+            halted: ctx.halted,
+        }
+    }
+
+    /// Check if the session was captured with the same opcode mapping as `instructions`
+    pub fn matches<P: ProgramStorage>(&self, instructions: &InstructionSet<P>) -> bool {
+        self.fingerprint == instructions.fingerprint()
+    }
+
+    /// Create a Vm continuing the session with `instructions`. The Vm is restored even if
+    /// `instructions` don't match the session, the mismatch is returned with it so the host can
+    /// warn about it.
+    pub fn restore<'a>(
+        &self,
+        instructions: InstructionSet<Vec<u8>>,
+    ) -> (Vm<'a, Vec<u8>>, Option<FingerprintMismatch>) {
+        let current = instructions.fingerprint();
+        let mismatch = (current != self.fingerprint).then_some(FingerprintMismatch {
+            saved: self.fingerprint,
+            current,
+        });
+
+        let mut vm = Vm::new(instructions, self.program.clone());
+        let ctx = &mut vm.ctx;
+        ctx.pc = self.pc;
+        ctx.stack = self.stack.clone();
+        ctx.auxiliary_stack = self.aux_stack.clone();
+        ctx.call_stack = self
+            .calls
+            .iter()
+            .map(|&(return_pc, end_pc)| Frame { return_pc, end_pc })
+            .collect();
+        ctx.halted = self.halted;
+        (vm, mismatch)
+    }
+
+    /// Write the session as JSON to `writer`
+    pub fn save(&self, writer: impl io::Write) -> Result<(), SessionError> {
+        Ok(serde_json::to_writer(writer, self)?)
+    }
+
+    /// Read a session saved with [`ReplSession::save`] from `reader`
+    pub fn load(reader: impl io::Read) -> Result<Self, SessionError> {
+        let session: Self = serde_json::from_reader(reader)?;
+        if session.format_version != FORMAT_VERSION {
+            return Err(SessionError::UnsupportedVersion {
+                found: session.format_version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FingerprintMismatch, ReplSession, SessionError, FORMAT_VERSION};
+    use crate::{Data, InstructionSet, RunOutcome, Vm};
+
+    /// Defines a word doubling the top value, keeps its address on the auxiliary stack and
+    /// calls it twice
+    const PROGRAM: &[u8] = b"[(2*)2+a 5 mda2c m2c p";
+
+    /// Restore `session` with the standard instructions, which have to match
+    fn restore<'a>(session: &ReplSession) -> Vm<'a, Vec<u8>> {
+        let (vm, mismatch) = session.restore(InstructionSet::standard());
+        assert_eq!(mismatch, None);
+        vm
+    }
+
+    /// Run a restored `vm` to its end, returning its output and main stack
+    fn finish(mut vm: Vm<Vec<u8>>) -> (String, Vec<Data>) {
+        let mut output = Vec::new();
+        vm.run_with_input_output(&[], &mut output).unwrap();
+        let stack = vm.get_context_mut().take_stack();
+        (String::from_utf8(output).unwrap(), stack)
+    }
+
+    fn round_trip(session: &ReplSession) -> ReplSession {
+        let mut saved = Vec::new();
+        session.save(&mut saved).unwrap();
+        ReplSession::load(saved.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn continues_as_if_never_interrupted() {
+        let uninterrupted = finish(Vm::new(InstructionSet::standard(), PROGRAM.to_vec()));
+        assert_eq!(uninterrupted.0, "20");
+
+        // Interrupting after every instruction covers the pending word definition, values on
+        // both stacks and a running call
+        for steps in 0.. {
+            let mut printed = Vec::new();
+            let mut vm = Vm::new(InstructionSet::standard(), PROGRAM.to_vec())
+                .with_io_sandbox(Vec::new(), &mut printed);
+            let outcome = vm.run_with_limit(steps).unwrap();
+            let session = round_trip(&ReplSession::capture(&vm));
+            assert_eq!(session, ReplSession::capture(&vm));
+            drop(vm);
+
+            let (output, stack) = finish(restore(&session));
+            let output = String::from_utf8(printed).unwrap() + &output;
+            assert_eq!((output, stack), uninterrupted);
+            if outcome == RunOutcome::Completed {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn program_can_grow_after_restoring() {
+        let mut vm = Vm::new(InstructionSet::standard(), b"[(3*)2+a".to_vec());
+        vm.run().unwrap();
+        let mut session = round_trip(&ReplSession::capture(&vm));
+
+        // The word defined before saving is still callable
+        session.program.extend(b"4 m2c p");
+        let (output, _) = finish(restore(&session));
+        assert_eq!(output, "12");
+    }
+
+    #[test]
+    fn fingerprint_mismatch() {
+        let vm = Vm::new(InstructionSet::standard(), b"1".to_vec());
+        let session = ReplSession::capture(&vm);
+
+        let mut instructions = InstructionSet::standard();
+        instructions.remove(&b'p');
+        let current = instructions.fingerprint();
+        assert!(!session.matches(&instructions));
+        // Restoring still works but reports the mismatch
+        let (vm, mismatch) = session.restore(instructions);
+        assert_eq!(
+            mismatch,
+            Some(FingerprintMismatch {
+                saved: InstructionSet::<Vec<u8>>::standard().fingerprint(),
+                current,
+            })
+        );
+        let (_, stack) = finish(vm);
+        assert_eq!(stack, vec![Data::Int(1)]);
+    }
+
+    #[test]
+    fn load_errors() {
+        let vm = Vm::new(InstructionSet::standard(), b"1".to_vec());
+        let mut session = ReplSession::capture(&vm);
+        session.format_version = FORMAT_VERSION + 1;
+        let mut saved = Vec::new();
+        session.save(&mut saved).unwrap();
+        assert!(matches!(
+            ReplSession::load(saved.as_slice()),
+            Err(SessionError::UnsupportedVersion { found, .. }) if found == FORMAT_VERSION + 1
+        ));
+
+        assert!(matches!(
+            ReplSession::load(&b"{\"pc\": 1}"[..]),
+            Err(SessionError::Json(_))
+        ));
+    }
+}
@@ -0,0 +1,32 @@
+//! Helpers shared by the unit tests
+
+use crate::{Data, InstructionSet, Vm, VmResult};
+
+/// Run `program` with the standard instructions and no input.
+/// Returns the result, the main stack (bottom value first) and everything that was printed.
+pub(crate) fn run_full(program: &str) -> (VmResult, Vec<Data>, String) {
+    let mut output = Vec::new();
+    let mut vm =
+        Vm::new(InstructionSet::standard(), program).with_io_sandbox(Vec::new(), &mut output);
+    let res = vm.run();
+    let stack = vm.get_context_mut().take_stack();
+    drop(vm);
+    (res, stack, String::from_utf8(output).unwrap())
+}
+
+/// Run `program` and get the main stack, the bottom value comes first
+pub(crate) fn run(program: &str) -> VmResult<Vec<Data>> {
+    let (res, stack, _) = run_full(program);
+    res.map(|_| stack)
+}
+
+/// Run `program` and get everything it printed
+pub(crate) fn output(program: &str) -> VmResult<String> {
+    let (res, _, output) = run_full(program);
+    res.map(|_| output)
+}
+
+/// Run `program`, which has to succeed, and get the main stack
+pub(crate) fn stack(program: &str) -> Vec<Data> {
+    run(program).unwrap_or_else(|err| panic!("{:?} failed: {}", program, err))
+}
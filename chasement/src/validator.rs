@@ -0,0 +1,260 @@
+//! Checks of programs beyond executing them.
+//!
+//! Currently these are stack effect comments like `#( a b -- sum )#`. The names are only
+//! documentation, the number of inputs and outputs declares how the word changes the stack depth.
+//! With [`crate::Vm::enable_effect_checks`] the Vm records the depth after such a comment and
+//! checks the change once the next stack effect comment is reached on the same call depth, the
+//! surrounding call returns or the program ends.
+//!
+//! A program can also start with a version header like `#version 0.1#`, which the Vm checks
+//! against the language version of its instruction set before running it.
+
+use crate::{instructions::Syntax, Context, ProgramStorage, VmError, VmResult};
+
+/// The declared effect of a word on the stack
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackEffect {
+    /// Names of the values taken from the stack, the top value comes last
+    pub inputs: Vec<String>,
+    /// Names of the values left on the stack, the top value comes last
+    pub outputs: Vec<String>,
+}
+
+impl StackEffect {
+    /// Parse the text of a comment like `( a b -- sum )`.
+    /// Returns `None` if it is a plain comment.
+    pub fn parse(text: &str) -> Option<Self> {
+        let inner = text.trim().strip_prefix('(')?.strip_suffix(')')?;
+        let words = inner.split_whitespace().collect::<Vec<_>>();
+        let separator = words.iter().position(|word| *word == "--")?;
+        let (inputs, outputs) = (&words[..separator], &words[separator + 1..]);
+        if outputs.contains(&"--") {
+            return None;
+        }
+
+        let to_names = |words: &[&str]| words.iter().map(|word| word.to_string()).collect();
+        let (inputs, outputs) = (to_names(inputs), to_names(outputs));
+        Some(Self { inputs, outputs })
+    }
+
+    /// Parse the comment starting with the comment opcode at `pc`.
+    /// Like the comment instruction the comment ends at the next comment opcode or newline.
+    pub fn parse_comment<P: ProgramStorage>(
+        program: &P,
+        pc: usize,
+        syntax: &Syntax,
+    ) -> Option<Self> {
+        Self::parse(&comment_text(program, pc, syntax)?)
+    }
+
+    /// Get the change of the stack depth, the number of outputs minus the number of inputs
+    pub fn delta(&self) -> i64 {
+        self.outputs.len() as i64 - self.inputs.len() as i64
+    }
+}
+
+/// Parse the version header at the start of `program`, a comment like `#version 1.2#`.
+/// Returns the (major, minor) version or `Ok(None)` if the program has no header. Every comment
+/// at the start whose first word is "version" is a header, if it doesn't contain a version
+/// like `#version 300.0#` its text is returned as error.
+pub fn parse_version_header<P: ProgramStorage>(
+    program: &P,
+    syntax: &Syntax,
+) -> Result<Option<(u8, u8)>, String> {
+    let text = match comment_text(program, 0, syntax) {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+    let mut words = text.split_whitespace();
+    if words.next() != Some("version") {
+        return Ok(None);
+    }
+
+    let version = match (words.next(), words.next()) {
+        (Some(version), None) => version.split_once('.'),
+        _ => None,
+    };
+    match version.map(|(major, minor)| (major.parse(), minor.parse())) {
+        Some((Ok(major), Ok(minor))) => Ok(Some((major, minor))),
+        _ => Err(text),
+    }
+}
+
+/// Get the text of the comment starting with the comment opcode at `pc`, up to the next
+/// comment opcode or newline
+fn comment_text<P: ProgramStorage>(program: &P, pc: usize, syntax: &Syntax) -> Option<String> {
+    if program.opcode_at(pc)? != syntax.comment {
+        return None;
+    }
+
+    let mut text = Vec::new();
+    let mut idx = pc + 1;
+    while let Some(op) = program.opcode_at(idx) {
+        if op == syntax.comment || op == b'\n' {
+            break;
+        }
+        text.push(op);
+        idx += 1;
+    }
+
+    String::from_utf8(text).ok()
+}
+
+/// A region after a stack effect comment which was not checked yet
+#[derive(Clone, Debug)]
+struct Region {
+    comment_pc: usize,
+    declared: i64,
+    start_depth: usize,
+    call_depth: usize,
+}
+
+/// Runtime state of the stack effect checks
+#[derive(Clone, Debug, Default)]
+pub(crate) struct EffectChecker {
+    regions: Vec<Region>,
+}
+
+impl EffectChecker {
+    /// Called before the instruction at the program counter is executed
+    pub(crate) fn before_instruction<P: ProgramStorage>(&mut self, ctx: &Context<P>) -> VmResult {
+        let effect = match StackEffect::parse_comment(&ctx.program, ctx.pc, &ctx.syntax) {
+            Some(effect) => effect,
+            None => return Ok(()),
+        };
+
+        if self
+            .regions
+            .last()
+            .filter(|region| region.call_depth == ctx.call_depth())
+            .is_some()
+        {
+            self.close_last(ctx)?;
+        }
+
+        self.regions.push(Region {
+            comment_pc: ctx.pc,
+            declared: effect.delta(),
+            start_depth: ctx.stack_len(),
+            call_depth: ctx.call_depth(),
+        });
+        Ok(())
+    }
+
+    /// Called after an instruction was executed, checks the regions of finished calls
+    pub(crate) fn after_instruction<P: ProgramStorage>(&mut self, ctx: &Context<P>) -> VmResult {
+        while self
+            .regions
+            .last()
+            .filter(|region| region.call_depth > ctx.call_depth())
+            .is_some()
+        {
+            self.close_last(ctx)?;
+        }
+        Ok(())
+    }
+
+    /// Called once the program ended, checks all remaining regions
+    pub(crate) fn finish<P: ProgramStorage>(&mut self, ctx: &Context<P>) -> VmResult {
+        while !self.regions.is_empty() {
+            self.close_last(ctx)?;
+        }
+        Ok(())
+    }
+
+    fn close_last<P: ProgramStorage>(&mut self, ctx: &Context<P>) -> VmResult {
+        if let Some(region) = self.regions.pop() {
+            let observed = ctx.stack_len() as i64 - region.start_depth as i64;
+            if observed != region.declared {
+                return Err(VmError::StackEffect {
+                    declared: region.declared,
+                    observed,
+                    comment_pc: region.comment_pc,
+                    pc: ctx.pc,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StackEffect;
+    use crate::{InstructionSet, Vm, VmError, VmResult};
+
+    fn checked(program: &str) -> VmResult {
+        let mut vm = Vm::new(InstructionSet::standard(), program);
+        vm.enable_effect_checks();
+        vm.run()
+    }
+
+    /// A program defining `body` as a word with '[' and calling it on 1 2 and then on the
+    /// result and 3
+    fn calling_twice(body: &str) -> String {
+        let len = body.len();
+        format!("[({})2+a 1 2 mda{}c 3 m{}c", body, len, len)
+    }
+
+    #[test]
+    fn parse() {
+        let effect = StackEffect::parse("( a b -- sum )").unwrap();
+        assert_eq!(effect.inputs, vec!["a", "b"]);
+        assert_eq!(effect.outputs, vec!["sum"]);
+        assert_eq!(effect.delta(), -1);
+        assert_eq!(StackEffect::parse(" ( -- ) ").unwrap().delta(), 0);
+        assert_eq!(StackEffect::parse("(-- x y)").unwrap().delta(), 2);
+        assert_eq!(StackEffect::parse("a plain comment"), None);
+        assert_eq!(StackEffect::parse("( a b )"), None);
+        assert_eq!(StackEffect::parse("( a -- b -- c )"), None);
+    }
+
+    #[test]
+    fn correct_word() {
+        assert_eq!(checked(&calling_twice("#( a b -- sum )#+")), Ok(()));
+        // Consecutive comments on the top level each start a new region
+        assert_eq!(checked("#( -- a )#1#( a -- )#o#( -- )#"), Ok(()));
+    }
+
+    #[test]
+    fn off_by_one_word() {
+        let program = calling_twice("#( a b -- sum )#+d");
+        assert!(matches!(
+            checked(&program),
+            Err(VmError::StackEffect {
+                declared: -1,
+                observed: 0,
+                comment_pc: 2,
+                ..
+            })
+        ));
+        // Without the checks the program runs
+        assert_eq!(Vm::new(InstructionSet::standard(), &*program).run(), Ok(()));
+        assert!(matches!(
+            checked("#( -- a )#1 2"),
+            Err(VmError::StackEffect {
+                declared: 1,
+                observed: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn nested_words() {
+        // The word's region is checked when the call returns, the outer one at the end
+        let program = format!("#( -- n )#{}", calling_twice("#( a b -- sum )#+"));
+        assert_eq!(checked(&program), Ok(()));
+
+        let program = format!("#( -- )#{}", calling_twice("#( a b -- sum )#+"));
+        assert!(matches!(
+            checked(&program),
+            Err(VmError::StackEffect {
+                declared: 0,
+                observed: 1,
+                comment_pc: 0,
+                ..
+            })
+        ));
+    }
+}